@@ -23,27 +23,9 @@ pub async fn send_files(ctx: UnifyedContext) {
     ctx.send_attachment_files(
         "пива бы",
         vec![
-            File {
-                filename: "pivo.jpg".to_string(),
-                content: tokio::fs::read("./examples/commands/pivo.jpg")
-                    .await
-                    .unwrap(),
-                ftype: FileType::Photo,
-            },
-            File {
-                filename: "pivo1.jpg".to_string(),
-                content: tokio::fs::read("./examples/commands/pivo1.jpg")
-                    .await
-                    .unwrap(),
-                ftype: FileType::Photo,
-            },
-            File {
-                filename: "pivo2.jpg".to_string(),
-                content: tokio::fs::read("./examples/commands/pivo2.jpg")
-                    .await
-                    .unwrap(),
-                ftype: FileType::Photo,
-            },
+            File::from_path("./examples/commands/pivo.jpg", FileType::Photo),
+            File::from_path("./examples/commands/pivo1.jpg", FileType::Photo),
+            File::from_path("./examples/commands/pivo2.jpg", FileType::Photo),
         ],
     )
     .await;
@@ -8,60 +8,133 @@ pub mod api_requests;
 /// This module contains low level functions for file requests and etc
 pub mod requests;
 
+/// Outgoing rate limiter for VK and Telegram requests
+///
+/// This module contains token bucket limiter used by api calls and file uploads
+pub mod rate_limit;
+
+/// Batching of VK API calls into `execute` requests
+///
+/// This module contains function for sending VK API calls in batches
+pub mod batch;
+
+/// Storage of longpoll offsets
+///
+/// This module contains offset store trait and its in-memory and file implementations
+pub mod offset_store;
+
+/// Pluggable HTTP transport
+///
+/// This module contains transport trait used for all HTTP requests and its default hyper implementation
+pub mod transport;
+
+/// HTTP CONNECT and SOCKS5 proxy support
+///
+/// This module contains hyper connector which tunnels connections through a proxy
+pub mod proxy;
+
+/// Detection of file MIME types and names
+///
+/// This module contains magic bytes sniffing and filename helpers used for downloaded files
+pub mod mime;
+
 pub mod structs;
-use log::{debug, info, log_enabled};
+use api_requests::{ApiError, api_call};
+use log::{debug, error, info, warn};
+use offset_store::OffsetStore;
 use requests::*;
+use serde_json::Value;
+use std::future::{Future, pending};
+use std::sync::Arc;
 use std::time::Duration;
-use std::{panic, sync::Arc};
-use tokio::time::interval;
-use tokio::{select, sync::Mutex};
-use tokio::{
-    sync::mpsc::{Receiver, Sender, channel},
-    time::Instant,
-};
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::{Instant, sleep, timeout_at};
 
+use crate::dispatcher::{Dispatcher, UpdateSender};
 use crate::structs::config::Config;
-use crate::structs::context::{UnifyContext, UnifyedContext};
+use crate::structs::context::{Platform, UnifyContext, UnifyedContext};
 use crate::structs::middleware::MiddlewareChain;
 use crate::structs::struct_to_vec::param;
-use crate::structs::tg::TGGetUpdates;
-use crate::structs::vk::{VKGetServerResponse, VKGetUpdates, VKTs};
-
-async fn get_vk_updates(
-    server: &mut str,
-    key: &mut str,
-    ts: &mut String,
-    tx: &Sender<UnifyedContext>,
+use crate::structs::tg::{TGGetUpdates, TGUpdate};
+use crate::structs::vk::{VKGetServer, VKGetServerResponse, VKGetUpdates, VKHistoryLost};
+
+const LONGPOLL_ERROR_DELAY: Duration = Duration::from_secs(3);
+
+// Updates are returned with `event_id` for deduplication
+pub(crate) async fn get_vk_updates(
+    longpoll: &mut VKGetServer,
     config: Arc<Config>,
-) {
-    let get_updates = request(
-        server,
+) -> Vec<(Option<String>, UnifyedContext)> {
+    let get_updates = request_with_timeout(
+        &longpoll.server,
         &config.vk_access_token,
         vec![
             param("act", "a_check"),
-            param("key", key.to_string()),
-            param("ts", ts.to_string()),
-            param("wait", "25"),
+            param("key", longpoll.key.as_str()),
+            param("ts", longpoll.ts.as_str()),
+            param("wait", config.timeouts.longpoll_wait.as_secs().to_string()),
         ],
+        Platform::VK,
+        &config,
+        config.timeouts.longpoll,
     )
-    .await;
-
-    // REFACTOR: need to refactor this as soon as posible
-    let empty_str = "".to_string();
-    let updates_str = get_updates.as_ref().unwrap_or(&empty_str);
-    let updates: VKGetUpdates = serde_json::from_str(updates_str).unwrap_or(VKGetUpdates {
-        ts: ts.clone(),
-        updates: Some(vec![]),
-        failed: Some(1),
-    });
+    .await
+    .map(|(_, body)| body);
 
-    *ts = updates.ts;
+    let updates_str = match get_updates {
+        Ok(updates_str) => updates_str,
+        Err(err) => {
+            error!("[LONGPOLL] [VK] Failed to get updates: {}", err);
+            sleep(LONGPOLL_ERROR_DELAY).await;
+            return vec![];
+        }
+    };
+    let updates: VKGetUpdates = match serde_json::from_str(&updates_str) {
+        Ok(updates) => updates,
+        Err(err) => {
+            error!(
+                "[LONGPOLL] [VK] Invalid longpoll response: {} ({})",
+                err, updates_str
+            );
+            sleep(LONGPOLL_ERROR_DELAY).await;
+            return vec![];
+        }
+    };
 
-    if updates.failed.is_some() {
-        if let Ok(vk_ts) = serde_json::from_str::<VKTs>(updates_str) {
-            *ts = vk_ts.ts;
+    match updates.failed {
+        None => {}
+        Some(1) => {
+            let new_ts = updates.ts.unwrap_or_else(|| longpoll.ts.clone());
+            warn!(
+                "[LONGPOLL] [VK] Event history is outdated or partially lost, continuing from ts {}",
+                new_ts
+            );
+            let lost = VKHistoryLost {
+                ts: std::mem::replace(&mut longpoll.ts, new_ts.clone()),
+                new_ts,
+            };
+            return vec![(None, lost.unify(config.clone()))];
+        }
+        Some(2) => {
+            warn!("[LONGPOLL] [VK] Longpoll key expired, requesting new one");
+            let server = get_vk_settings(config.clone()).await;
+            longpoll.key = server.key;
+            longpoll.server = server.server;
+            return vec![];
+        }
+        Some(failed) => {
+            warn!(
+                "[LONGPOLL] [VK] Longpoll key and ts are lost (failed: {}), requesting new ones",
+                failed
+            );
+            *longpoll = get_vk_settings(config.clone()).await;
+            return vec![];
         }
-        return;
+    }
+
+    if let Some(ts) = updates.ts {
+        longpoll.ts = ts;
     }
 
     let vk_updates = updates.updates.unwrap_or_default();
@@ -70,67 +143,105 @@ async fn get_vk_updates(
         vk_updates.len()
     );
 
-    for update in vk_updates {
-        let unified = update.unify(config.clone());
-        tx.send(unified).await.unwrap();
-    }
+    vk_updates
+        .iter()
+        .map(|update| (update.event_id.clone(), update.unify(config.clone())))
+        .collect()
 }
 
-async fn get_vk_settings(config: Arc<Config>) -> VKGetServerResponse {
-    let vk_group_id = config.vk_group_id.to_string();
-    let get_server = request(
-        "https://api.vk.com/method/groups.getLongPollServer",
-        &config.vk_access_token,
-        vec![
-            param("group_id", vk_group_id),
-            param("v", &config.vk_api_version),
-        ],
-    )
-    .await
-    .unwrap();
-
-    let server: VKGetServerResponse = serde_json::from_str(&get_server).unwrap();
-    debug!("[LONGPOLL] [VK] Got longpoll server: {:?}", server);
+async fn get_vk_settings(config: Arc<Config>) -> VKGetServer {
+    loop {
+        let get_server = api_call(
+            Platform::VK,
+            "groups.getLongPollServer",
+            vec![param("group_id", config.vk_group_id.to_string())],
+            &config,
+        )
+        .await
+        .and_then(|server| Ok(serde_json::from_value::<VKGetServerResponse>(server)?));
 
-    server
+        match get_server {
+            Ok(server) => {
+                debug!("[LONGPOLL] [VK] Got longpoll server: {:?}", server);
+                return server.response;
+            }
+            Err(err) => {
+                error!("[LONGPOLL] [VK] Failed to get longpoll server: {}", err);
+                sleep(LONGPOLL_ERROR_DELAY).await;
+            }
+        }
+    }
 }
 
-async fn get_tg_updates(offset: &mut i64, tx: &Sender<UnifyedContext>, config: Arc<Config>) {
-    let get_updates = request(
-        &format!(
-            "https://api.telegram.org/{}/getUpdates",
-            config.tg_access_token
-        ),
+async fn get_tg_updates(offset: i64, config: Arc<Config>) -> Vec<TGUpdate> {
+    let wait = config.timeouts.longpoll_wait.as_secs().to_string();
+    let mut params = vec![
+        param("timeout", wait),
+        param("offset", offset.to_string()),
+        param("limit", "100"),
+    ];
+    if let Some(allowed_updates) = config
+        .tg_updates
+        .as_ref()
+        .and_then(|updates| updates.allowed_updates_param())
+    {
+        params.push(param("allowed_updates", allowed_updates));
+    }
+    let get_updates = request_with_timeout(
+        &config.tg_method_url("getUpdates"),
         "",
-        vec![
-            param("timeout", "25"),
-            param("offset", offset.to_string()),
-            param("limit", "100"),
-        ],
+        params,
+        Platform::Telegram,
+        &config,
+        config.timeouts.longpoll,
     )
-    .await;
+    .await
+    .map(|(_, body)| body);
 
-    let updates: TGGetUpdates = serde_json::from_str(&get_updates.unwrap_or("".to_string()))
-        .unwrap_or(TGGetUpdates {
-            ok: false,
-            result: vec![],
-        });
+    let updates_str = match get_updates {
+        Ok(updates_str) => updates_str,
+        Err(err) => {
+            error!("[LONGPOLL] [TELEGRAM] Failed to get updates: {}", err);
+            sleep(LONGPOLL_ERROR_DELAY).await;
+            return vec![];
+        }
+    };
+    let response: Value = match serde_json::from_str(&updates_str) {
+        Ok(response) => response,
+        Err(err) => {
+            error!(
+                "[LONGPOLL] [TELEGRAM] Invalid longpoll response: {} ({})",
+                err, updates_str
+            );
+            sleep(LONGPOLL_ERROR_DELAY).await;
+            return vec![];
+        }
+    };
+    // Wrong token (401) and set webhook (409) are returned with `ok: false`
+    let updates = match response.get("ok").and_then(Value::as_bool) {
+        Some(true) => serde_json::from_value::<TGGetUpdates>(response).map_err(ApiError::from),
+        _ => Err(ApiError::from_tg_response(&response)),
+    };
+    let updates = match updates {
+        Ok(updates) => updates,
+        Err(err) => {
+            error!("[LONGPOLL] [TELEGRAM] Failed to get updates: {}", err);
+            let retry_after = Duration::from_secs(err.retry_after().unwrap_or(0).max(0) as u64);
+            sleep(retry_after.max(LONGPOLL_ERROR_DELAY)).await;
+            return vec![];
+        }
+    };
     debug!(
         "[LONGPOLL] [TELEGRAM] Got {} updates, processing",
         updates.result.len()
     );
 
-    for update in updates.result {
-        let unified = update.unify(config.clone());
-        tx.send(unified).await.unwrap();
-
-        *offset = update.update_id + 1;
-    }
+    updates.result
 }
 
 ///Starts longpoll client for getting updates from VK and Telegram
 ///
-///Accepts middleware chain and config
+///Accepts middleware chain and config. Only platforms with access token set in config are polled
 ///
 ///# Examples
 ///
@@ -170,53 +281,240 @@ async fn get_tg_updates(offset: &mut i64, tx: &Sender<UnifyedContext>, config: A
 ///}
 ///```
 pub async fn start_longpoll_client(middleware: MiddlewareChain, config: Config) {
+    start_longpoll_client_with_shutdown(middleware, config, pending()).await;
+}
+
+///Starts longpoll client and runs it until `shutdown` future completes
+///
+///On shutdown polling is stopped, updates in processing are awaited (up to `config.shutdown_timeout`), then function returns
+///
+///# Examples
+///
+///```no_run
+///use vtg::{
+///    client::start_longpoll_client_with_shutdown,
+///    structs::{config::Config, middleware::MiddlewareChain},
+///};
+///
+///#[tokio::main]
+///async fn main() {
+///    let config = Config {
+///        tg_access_token: "TG_ACCESS_TOKEN".to_string(),
+///        ..Default::default()
+///    };
+///
+///    start_longpoll_client_with_shutdown(MiddlewareChain::new(), config, async {
+///        tokio::signal::ctrl_c().await.unwrap();
+///    })
+///    .await;
+///    // flush your own state here
+///}
+///```
+pub async fn start_longpoll_client_with_shutdown<F>(
+    middleware: MiddlewareChain,
+    config: Config,
+    shutdown: F,
+) where
+    F: Future<Output = ()>,
+{
+    start_longpoll_clients_with_shutdown(vec![(middleware, config)], shutdown).await;
+}
+
+///Starts longpoll clients for several bot accounts
+///
+///Accepts pairs of middleware chain and config, every account is polled independently and gets own dispatcher
+///
+///# Examples
+///
+///```no_run
+///use vtg::{
+///    client::start_longpoll_clients,
+///    structs::{config::Config, middleware::MiddlewareChain},
+///};
+///
+///#[tokio::main]
+///async fn main() {
+///    let first = Config {
+///        name: "first".to_string(),
+///        tg_access_token: "TG_ACCESS_TOKEN_1".to_string(),
+///        ..Default::default()
+///    };
+///    let second = Config {
+///        name: "second".to_string(),
+///        vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+///        vk_group_id: 123456789,
+///        ..Default::default()
+///    };
+///
+///    let middleware_chain = MiddlewareChain::new();
+///    start_longpoll_clients(vec![
+///        (middleware_chain.clone(), first),
+///        (middleware_chain, second),
+///    ])
+///    .await;
+///}
+///```
+pub async fn start_longpoll_clients(bots: Vec<(MiddlewareChain, Config)>) {
+    start_longpoll_clients_with_shutdown(bots, pending()).await;
+}
+
+///Starts longpoll clients for several bot accounts and runs them until `shutdown` future completes
+pub async fn start_longpoll_clients_with_shutdown<F>(
+    bots: Vec<(MiddlewareChain, Config)>,
+    shutdown: F,
+) where
+    F: Future<Output = ()>,
+{
     info!("Start getting updates...");
-    let config = Arc::new(config.check());
-    let vk_settings = get_vk_settings(config.clone()).await;
-    let mut server = vk_settings.response.server;
-    let mut key = vk_settings.response.key;
-    let mut ts = vk_settings.response.ts;
-    let mut offset: i64 = 0;
-
-    let (tx, rx): (Sender<UnifyedContext>, Receiver<UnifyedContext>) = channel(100);
-    let rx = Arc::new(Mutex::new(rx));
-    let middleware = Arc::new(middleware);
-    for _i in 0..4 {
-        let rx_clone = Arc::clone(&rx);
-        let middleware_clone = Arc::clone(&middleware);
-        tokio::task::spawn(async move {
-            loop {
-                if let Some(update) = rx_clone.lock().await.recv().await {
-                    if log_enabled!(log::Level::Debug) {
-                        let start_time = Instant::now();
-                        middleware_clone.execute(update).await;
-                        let end_time = Instant::now();
-                        let elapsed_time = end_time.duration_since(start_time);
-                        debug!("Processing time: {:?}", elapsed_time);
-                    } else {
-                        middleware_clone.execute(update).await
-                    }
-                }
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut pollers = Vec::new();
+    let mut dispatchers = Vec::new();
+    let mut shutdown_timeout = Duration::ZERO;
+    for (middleware, config) in bots {
+        let config = Arc::new(config.check());
+        let dispatcher = Dispatcher::start(middleware, &config);
+        if config.vk_enabled() {
+            pollers.push(tokio::task::spawn(poll_vk(
+                dispatcher.sender(),
+                config.clone(),
+                stop_rx.clone(),
+            )));
+        }
+        if config.tg_enabled() {
+            pollers.push(tokio::task::spawn(poll_tg(
+                dispatcher.sender(),
+                config.clone(),
+                stop_rx.clone(),
+            )));
+        }
+        shutdown_timeout = shutdown_timeout.max(config.shutdown_timeout());
+        dispatchers.push(dispatcher);
+    }
+
+    shutdown.await;
+    info!("Shutting down...");
+    stop_tx.send(true).ok();
+    // Poller can be blocked sending an update to a full queue, so it shares the dispatchers' deadline
+    let deadline = Instant::now() + shutdown_timeout;
+    for poller in pollers {
+        let abort = poller.abort_handle();
+        match timeout_at(deadline, poller).await {
+            Ok(Err(err)) => error!("Longpoll task failed: {}", err),
+            Ok(Ok(())) => {}
+            Err(_) => {
+                warn!("Longpoll task didn't stop in time, aborting");
+                abort.abort();
             }
-        });
+        }
+    }
+    for dispatcher in dispatchers {
+        dispatcher
+            .shutdown(deadline.saturating_duration_since(Instant::now()))
+            .await;
     }
+}
 
-    let mut interval = interval(Duration::from_secs(600));
+async fn poll_vk(tx: UpdateSender, config: Arc<Config>, mut stop: watch::Receiver<bool>) {
+    let store = config.offset_store.clone();
+    let stored = store
+        .as_ref()
+        .and_then(|store| store.vk_state(config.vk_group_id));
+    let mut longpoll = match stored.clone() {
+        Some(longpoll) => {
+            debug!("[LONGPOLL] [VK] Resuming from ts {}", longpoll.ts);
+            longpoll
+        }
+        None => select! {
+            longpoll = get_vk_settings(config.clone()) => longpoll,
+            _ = stop.wait_for(|stop| *stop) => return,
+        },
+    };
+    let mut saved = stored;
     loop {
-        let vk_task = get_vk_updates(&mut server, &mut key, &mut ts, &tx, config.clone());
-        let tg_task = get_tg_updates(&mut offset, &tx, config.clone());
+        // Only requests are cancelled on shutdown, received updates are always dispatched
+        let updates = select! {
+            updates = get_vk_updates(&mut longpoll, config.clone()) => updates,
+            _ = stop.wait_for(|stop| *stop) => return,
+        };
+        for (event_id, update) in updates {
+            if tx.send_unique(event_id.as_deref(), update).await.is_err() {
+                return;
+            }
+        }
+        // Most rounds end without updates, the store is written only when the position changes
+        if let Some(store) = &store
+            && saved.as_ref() != Some(&longpoll)
+        {
+            let (group_id, state) = (config.vk_group_id, longpoll.clone());
+            save_position(store, move |store| store.set_vk_state(group_id, &state)).await;
+            saved = Some(longpoll.clone());
+        }
+    }
+}
 
+async fn poll_tg(tx: UpdateSender, config: Arc<Config>, mut stop: watch::Receiver<bool>) {
+    let store = config.offset_store.clone();
+    let bot_id = config.tg_bot_id().to_string();
+    let mut offset = store
+        .as_ref()
+        .and_then(|store| store.tg_offset(&bot_id))
+        .unwrap_or(0);
+    if config
+        .tg_updates
+        .as_ref()
+        .is_some_and(|updates| updates.drop_pending_updates)
+    {
+        let drop_pending = api_call(
+            Platform::Telegram,
+            "deleteWebhook",
+            vec![param("drop_pending_updates", "true")],
+            &config,
+        );
         select! {
-            _ = vk_task => {
-            },
-            _ = tg_task => {
-            },
-            _ = interval.tick() => {
-            let vk_settings = get_vk_settings(config.clone()).await;
-            server = vk_settings.response.server;
-            key = vk_settings.response.key;
-            ts = vk_settings.response.ts;
+            result = drop_pending => if let Err(err) = result {
+                error!("[LONGPOLL] [TELEGRAM] Failed to drop pending updates: {}", err);
             },
+            _ = stop.wait_for(|stop| *stop) => return,
+        }
+    }
+    loop {
+        let updates = select! {
+            updates = get_tg_updates(offset, config.clone()) => updates,
+            _ = stop.wait_for(|stop| *stop) => return,
+        };
+        let received = offset;
+        let mut closed = false;
+        for update in updates {
+            let update_id = update.update_id.to_string();
+            if tx
+                .send_unique(Some(&update_id), update.unify(config.clone()))
+                .await
+                .is_err()
+            {
+                closed = true;
+                break;
+            }
+            offset = update.update_id + 1;
+        }
+        if let Some(store) = &store
+            && offset != received
+        {
+            let bot_id = bot_id.clone();
+            save_position(store, move |store| store.set_tg_offset(&bot_id, offset)).await;
+        }
+        if closed {
+            return;
         }
     }
 }
+
+// Stores may write to disk, so positions are saved on the blocking pool instead of a runtime worker
+async fn save_position<F>(store: &Arc<dyn OffsetStore>, save: F)
+where
+    F: FnOnce(&dyn OffsetStore) + Send + 'static,
+{
+    let store = store.clone();
+    if let Err(err) = tokio::task::spawn_blocking(move || save(store.as_ref())).await {
+        error!("[LONGPOLL] Failed to save longpoll position: {}", err);
+    }
+}
@@ -1,12 +1,209 @@
 use std::borrow::Cow;
+use std::fmt;
+use std::time::Duration;
 
-use log::debug;
+use hyper::StatusCode;
+use log::{debug, warn};
+use rand::Rng;
+use serde::Deserialize;
 use serde_json::Value;
+use tokio::time::sleep;
 
-use crate::structs::{context::Platform, struct_to_vec::param};
+use crate::structs::{config::RetrySettings, context::Platform, struct_to_vec::param};
 
 use super::*;
+
+/// VK request parameter echoed back in an API error
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct VKRequestParam {
+    pub key: String,
+    pub value: String,
+}
+
+/// Additional information about a failed Telegram request
+/// # Fields
+/// * `retry_after` - Seconds to wait before the request can be repeated (flood control)
+/// * `migrate_to_chat_id` - The group has been migrated to a supergroup with this ID
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TGResponseParameters {
+    pub retry_after: Option<i64>,
+    pub migrate_to_chat_id: Option<i64>,
+}
+
+/// Error returned by [`api_call`]
+/// # Variants
+/// * `VK` - VK API returned an `error` object
+/// * `Telegram` - Telegram API returned `ok: false`
+/// * `Request` - Request could not be sent or the response could not be read
+/// * `Json` - Response body is not valid JSON
+/// * `Status` - Response has a non-success HTTP status and no API error in the body
+/// * `Upload` - VK upload server rejected the file or returned an unexpected reply
+/// * `NotConfigured` - Platform has no access token in config
+#[derive(Debug)]
+pub enum ApiError {
+    VK {
+        error_code: i64,
+        error_msg: String,
+        request_params: Vec<VKRequestParam>,
+    },
+    Telegram {
+        error_code: i64,
+        description: String,
+        parameters: Option<TGResponseParameters>,
+    },
+    Request(HyperRequestError),
+    Json(serde_json::Error),
+    Status {
+        status: StatusCode,
+        body: String,
+    },
+    Upload(String),
+    NotConfigured(Platform),
+}
+
+#[derive(Deserialize)]
+struct VKError {
+    error_code: i64,
+    #[serde(default)]
+    error_msg: String,
+    #[serde(default)]
+    request_params: Vec<VKRequestParam>,
+}
+
+#[derive(Deserialize)]
+struct TGError {
+    #[serde(default)]
+    error_code: i64,
+    #[serde(default)]
+    description: String,
+    parameters: Option<TGResponseParameters>,
+}
+
+impl ApiError {
+    /// Builds an error from a VK `error` object
+    pub fn from_vk_error(error: &Value) -> Self {
+        match VKError::deserialize(error) {
+            Ok(error) => ApiError::VK {
+                error_code: error.error_code,
+                error_msg: error.error_msg,
+                request_params: error.request_params,
+            },
+            Err(_) => ApiError::VK {
+                error_code: 0,
+                error_msg: error["error_msg"]
+                    .as_str()
+                    .unwrap_or("Unknown error")
+                    .to_string(),
+                request_params: vec![],
+            },
+        }
+    }
+    /// Builds an error from a Telegram response with `ok: false`
+    pub fn from_tg_response(response: &Value) -> Self {
+        match TGError::deserialize(response) {
+            Ok(error) => ApiError::Telegram {
+                error_code: error.error_code,
+                description: error.description,
+                parameters: error.parameters,
+            },
+            Err(_) => ApiError::Telegram {
+                error_code: 0,
+                description: "Unknown error".to_string(),
+                parameters: None,
+            },
+        }
+    }
+    /// Error code returned by VK or Telegram API, if any
+    pub fn error_code(&self) -> Option<i64> {
+        match self {
+            ApiError::VK { error_code, .. } | ApiError::Telegram { error_code, .. } => {
+                Some(*error_code)
+            }
+            _ => None,
+        }
+    }
+    /// Seconds to wait before retrying, as reported by Telegram flood control
+    pub fn retry_after(&self) -> Option<i64> {
+        match self {
+            ApiError::Telegram {
+                parameters: Some(parameters),
+                ..
+            } => parameters.retry_after,
+            _ => None,
+        }
+    }
+    /// New supergroup ID, if Telegram reports that the group was migrated
+    pub fn migrate_to_chat_id(&self) -> Option<i64> {
+        match self {
+            ApiError::Telegram {
+                parameters: Some(parameters),
+                ..
+            } => parameters.migrate_to_chat_id,
+            _ => None,
+        }
+    }
+    /// Whether the request failed before an API response was received
+    pub fn is_transport(&self) -> bool {
+        matches!(self, ApiError::Request(_))
+    }
+    /// Whether the request didn't complete within the configured timeout
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, ApiError::Request(e) if e.is_timeout())
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::VK {
+                error_code,
+                error_msg,
+                ..
+            } => write!(f, "VK API error {}: {}", error_code, error_msg),
+            ApiError::Telegram {
+                error_code,
+                description,
+                ..
+            } => write!(f, "Telegram API error {}: {}", error_code, description),
+            ApiError::Request(e) => write!(f, "Error while sending request: {}", e),
+            ApiError::Json(e) => write!(f, "Invalid JSON in response: {}", e),
+            ApiError::Status { status, body } => {
+                write!(f, "Unexpected HTTP status {}: {}", status, body)
+            }
+            ApiError::Upload(error) => write!(f, "Upload failed: {}", error),
+            ApiError::NotConfigured(platform) => {
+                write!(f, "{:?} is not configured", platform)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApiError::Request(e) => Some(e),
+            ApiError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<HyperRequestError> for ApiError {
+    fn from(e: HyperRequestError) -> Self {
+        ApiError::Request(e)
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        ApiError::Json(e)
+    }
+}
+
 /// Send request to VK or Telegram API
+///
+/// Requests are delayed according to `config.rate_limit` and failed calls are retried according to `config.retry`.
+/// VK calls are batched into `execute` requests if `config.vk_batch` is set
 /// # Arguments
 /// * `platform` - Platform to send request to
 /// * `method` - Method to call
@@ -14,11 +211,11 @@ use super::*;
 /// * `config` - Config to use
 ///
 /// # Returns
-/// * `Result<Value, String>` - Response from API
+/// * `Result<Value, ApiError>` - Response from API
 ///
 /// # Examples
 /// ```no_run
-/// use vtg::client::api_requests::api_call;
+/// use vtg::client::api_requests::{api_call, ApiError};
 /// use vtg::structs::{context::Platform, struct_to_vec::param};
 ///
 /// let response = api_call(Platform::VK, "messages.send", vec![param("peer_id", "1"), param("message", "Hello, world!")], &config).await;
@@ -26,6 +223,9 @@ use super::*;
 ///   Ok(response) => {
 ///      println!("Response: {}", response);
 ///   }
+///   Err(ApiError::VK { error_code: 901, .. }) => {
+///      println!("User didn't allow messages from the group");
+///   }
 ///   Err(e) => {
 ///      println!("Error: {}", e);
 ///   }
@@ -36,52 +236,124 @@ pub async fn api_call(
     method: &str,
     mut params: Vec<(Cow<'_, str>, Cow<'_, str>)>,
     config: &Config,
-) -> Result<Value, String> {
+) -> Result<Value, ApiError> {
+    if !config.is_enabled(&platform) {
+        return Err(ApiError::NotConfigured(platform));
+    }
+    if platform == Platform::VK && config.vk_batch.is_some() {
+        return Box::pin(batch::batch_call(method, params, config)).await;
+    }
+    if platform == Platform::VK {
+        params.push(param("v", &config.vk_api_version));
+    }
+    let mut attempt = 1;
+    loop {
+        let result = send_api_call(platform.clone(), method, params.clone(), config).await;
+        let (Err(error), Some(retry)) = (&result, &config.retry) else {
+            return result;
+        };
+        if attempt >= retry.max_attempts {
+            return result;
+        }
+        let Some(delay) = retry_delay(retry, &platform, attempt, error) else {
+            return result;
+        };
+        warn!(
+            "API call {} failed ({}), retrying in {:?} (attempt {}/{})",
+            method, error, delay, attempt, retry.max_attempts
+        );
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Returns delay before the next attempt, or `None` if the error shouldn't be retried
+pub(crate) fn retry_delay(
+    retry: &RetrySettings,
+    platform: &Platform,
+    attempt: u32,
+    error: &ApiError,
+) -> Option<Duration> {
+    let retryable = match error {
+        ApiError::VK { error_code, .. } => retry.vk_error_codes.contains(error_code),
+        ApiError::Telegram { error_code, .. } => retry.tg_error_codes.contains(error_code),
+        // Request which failed after it was sent may have been processed, repeating it can duplicate messages
+        ApiError::Request(e) if e.is_connect() => retry.retry_transport_errors,
+        ApiError::Request(_) => retry.retry_sent_requests,
+        ApiError::Status { status, .. } => retry.retry_sent_requests && status.is_server_error(),
+        ApiError::Json(_) | ApiError::Upload(_) | ApiError::NotConfigured(_) => false,
+    };
+    if !retryable {
+        return None;
+    }
+    if let (Platform::Telegram, Some(retry_after)) = (platform, error.retry_after()) {
+        // Repeating earlier would be rejected again, so a longer wait fails the call instead
+        let delay = Duration::from_secs(retry_after.max(0) as u64);
+        return (delay <= retry.max_delay).then_some(delay);
+    }
+    let delay = retry
+        .base_delay
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(retry.max_delay);
+    if retry.jitter && !delay.is_zero() {
+        let half = delay / 2;
+        return Some(half + half.mul_f64(rand::thread_rng().r#gen::<f64>()));
+    }
+    Some(delay)
+}
+
+async fn send_api_call(
+    platform: Platform,
+    method: &str,
+    params: Vec<(Cow<'_, str>, Cow<'_, str>)>,
+    config: &Config,
+) -> Result<Value, ApiError> {
     let url = match platform {
-        Platform::VK => format!("https://api.vk.com/method/{}", method),
-        Platform::Telegram => format!(
-            "https://api.telegram.org/{}/{}",
-            config.tg_access_token, method
-        ),
+        Platform::VK => config.vk_method_url(method),
+        Platform::Telegram => config.tg_method_url(method),
     };
     let access_token = match platform {
         Platform::VK => config.vk_access_token.clone(),
         Platform::Telegram => "".to_owned(),
     };
-    if platform == Platform::VK {
-        params.push(param("v", &config.vk_api_version));
-    }
-    let response = request(&url, &access_token, params).await;
-    match response {
-        Ok(response_text) => {
-            debug!("API call response text: {}", response_text);
-            let response_json: serde_json::Value =
-                serde_json::from_str(&response_text).map_err(|e| e.to_string())?;
-            match platform {
-                Platform::VK => {
-                    if let Some(error) = response_json.get("error") {
-                        let error_msg = error["error_msg"].as_str().unwrap_or("Unknown error");
-                        Err(error_msg.to_string())
-                    } else {
-                        Ok(response_json)
-                    }
-                }
-                Platform::Telegram => {
-                    if let Some(ok) = response_json.get("ok") {
-                        if ok.as_bool().unwrap_or(false) {
-                            Ok(response_json)
-                        } else {
-                            let error_msg = response_json["description"]
-                                .as_str()
-                                .unwrap_or("Unknown error");
-                            Err(error_msg.to_string())
-                        }
-                    } else {
-                        Err("Invalid response from Telegram API".to_string())
-                    }
-                }
+    let chat_id = params
+        .iter()
+        .find(|(key, _)| key == "chat_id")
+        .map(|(_, value)| value.to_string());
+    rate_limit::wait(platform.clone(), method, chat_id.as_deref(), config).await;
+    let (status, response_text) =
+        request_with_status(&url, &access_token, params, platform.clone(), config).await?;
+    debug!("API call response text: {}", response_text);
+    let response_json: Value = match serde_json::from_str(&response_text) {
+        Ok(response_json) => response_json,
+        Err(_) if !status.is_success() => {
+            return Err(ApiError::Status {
+                status,
+                body: response_text,
+            });
+        }
+        Err(e) => return Err(ApiError::Json(e)),
+    };
+    match platform {
+        Platform::VK => {
+            if let Some(error) = response_json.get("error") {
+                Err(ApiError::from_vk_error(error))
+            } else if !status.is_success() {
+                Err(ApiError::Status {
+                    status,
+                    body: response_text,
+                })
+            } else {
+                Ok(response_json)
             }
         }
-        Err(_) => Err("Error while sending request".to_string()),
+        Platform::Telegram => match response_json.get("ok").and_then(Value::as_bool) {
+            Some(true) => Ok(response_json),
+            Some(false) => Err(ApiError::from_tg_response(&response_json)),
+            None => Err(ApiError::Status {
+                status,
+                body: response_text,
+            }),
+        },
     }
 }
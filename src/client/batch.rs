@@ -0,0 +1,243 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use log::debug;
+use serde::de::Error as _;
+use serde_json::{Map, Value, json};
+use tokio::select;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio::sync::oneshot;
+use tokio::time::sleep;
+
+use super::api_requests::{ApiError, VKRequestParam, api_call};
+use super::requests::HyperRequestError;
+use crate::structs::config::{BatchSettings, Config};
+use crate::structs::context::Platform;
+use crate::structs::struct_to_vec::param;
+
+/// Maximum number of API calls in one `execute` request
+pub const MAX_EXECUTE_CALLS: usize = 25;
+
+struct BatchItem {
+    method: String,
+    params: Vec<(String, String)>,
+    tx: oneshot::Sender<Result<Value, ApiError>>,
+}
+
+lazy_static! {
+    static ref BATCHERS: Mutex<HashMap<String, UnboundedSender<BatchItem>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn is_batchable(method: &str) -> bool {
+    method != "execute"
+        && !method.is_empty()
+        && method
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_')
+}
+
+/// Sends VK API call as a part of the next `execute` request
+///
+/// Calls made within `config.vk_batch.window` are sent together, up to 25 in one request.
+/// Each caller gets its own response (`{"response": ...}`, like from a regular call) or error.
+///
+/// Falls back to a regular call for `execute` itself and for method names which can't be used in VKScript.
+///
+/// Note: batching worker is created once per VK access token, with settings from the first config used with it
+/// # Arguments
+/// * `method` - Method to call
+/// * `params` - Parameters to send
+/// * `config` - Config to use
+pub async fn batch_call(
+    method: &str,
+    params: Vec<(Cow<'_, str>, Cow<'_, str>)>,
+    config: &Config,
+) -> Result<Value, ApiError> {
+    if !is_batchable(method) {
+        let config = Config {
+            vk_batch: None,
+            ..config.clone()
+        };
+        return api_call(Platform::VK, method, params, &config).await;
+    }
+    let (tx, rx) = oneshot::channel();
+    let item = BatchItem {
+        method: method.to_string(),
+        params: params
+            .into_iter()
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect(),
+        tx,
+    };
+    sender(config).send(item).ok();
+    rx.await.unwrap_or_else(|_| {
+        Err(ApiError::Request(HyperRequestError::ResponseError(
+            "Batch worker stopped".to_string(),
+        )))
+    })
+}
+
+fn sender(config: &Config) -> UnboundedSender<BatchItem> {
+    let key = format!(
+        "{}:{}",
+        config.vk_method_url("execute"),
+        config.vk_access_token
+    );
+    let mut batchers = BATCHERS.lock().unwrap();
+    if let Some(tx) = batchers.get(&key)
+        && !tx.is_closed()
+    {
+        return tx.clone();
+    }
+    let settings = config.vk_batch.clone().unwrap_or_default();
+    let config = Config {
+        vk_batch: None,
+        ..config.clone()
+    };
+    let (tx, rx) = unbounded_channel();
+    tokio::task::spawn(run_batcher(config, settings, rx));
+    batchers.insert(key, tx.clone());
+    tx
+}
+
+async fn run_batcher(
+    config: Config,
+    settings: BatchSettings,
+    mut rx: UnboundedReceiver<BatchItem>,
+) {
+    let max_calls = settings.max_calls.clamp(1, MAX_EXECUTE_CALLS);
+    while let Some(first) = rx.recv().await {
+        let mut items = vec![first];
+        let window = sleep(settings.window);
+        tokio::pin!(window);
+        while items.len() < max_calls {
+            select! {
+                item = rx.recv() => match item {
+                    Some(item) => items.push(item),
+                    None => break,
+                },
+                _ = &mut window => break,
+            }
+        }
+        let config = config.clone();
+        tokio::task::spawn(async move { send_batch(items, &config).await });
+    }
+}
+
+async fn send_batch(items: Vec<BatchItem>, config: &Config) {
+    if items.len() == 1 {
+        let item = items.into_iter().next().unwrap();
+        let params = item.params.iter().map(|(k, v)| param(k, v)).collect();
+        let result = api_call(Platform::VK, &item.method, params, config).await;
+        item.tx.send(result).ok();
+        return;
+    }
+    let calls: Vec<(&str, &[(String, String)])> = items
+        .iter()
+        .map(|item| (item.method.as_str(), item.params.as_slice()))
+        .collect();
+    let code = execute_code(&calls);
+    debug!("[BATCH] [VK] Sending {} calls in execute", items.len());
+    match api_call(Platform::VK, "execute", vec![param("code", code)], config).await {
+        Ok(response) => {
+            let methods: Vec<&str> = items.iter().map(|item| item.method.as_str()).collect();
+            let results = split_execute_response(&methods, &response);
+            for (item, result) in items.into_iter().zip(results) {
+                item.tx.send(result).ok();
+            }
+        }
+        Err(error) => {
+            for item in items {
+                item.tx.send(Err(duplicate_error(&error))).ok();
+            }
+        }
+    }
+}
+
+/// Builds VKScript code which calls every method and returns array of their results
+pub fn execute_code(calls: &[(&str, &[(String, String)])]) -> String {
+    let calls: Vec<String> = calls
+        .iter()
+        .map(|(method, params)| {
+            let args: Map<String, Value> = params
+                .iter()
+                .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+                .collect();
+            format!("API.{}({})", method, Value::Object(args))
+        })
+        .collect();
+    format!("return [{}];", calls.join(","))
+}
+
+/// Splits `execute` response into results of the separate calls
+///
+/// Failed calls return `false` in the response array, their errors are matched from `execute_errors` in order
+pub fn split_execute_response(methods: &[&str], response: &Value) -> Vec<Result<Value, ApiError>> {
+    let values = response["response"].as_array().cloned().unwrap_or_default();
+    let mut errors = response["execute_errors"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+        .into_iter()
+        .peekable();
+    methods
+        .iter()
+        .enumerate()
+        .map(|(index, method)| {
+            let value = values.get(index).cloned().unwrap_or(Value::Bool(false));
+            if value == Value::Bool(false)
+                && let Some(error) = errors.next_if(|error| error["method"] == *method)
+            {
+                let mut error = ApiError::from_vk_error(&error);
+                if let ApiError::VK { request_params, .. } = &mut error {
+                    request_params.push(VKRequestParam {
+                        key: "method".to_string(),
+                        value: method.to_string(),
+                    });
+                }
+                return Err(error);
+            }
+            Ok(json!({ "response": value }))
+        })
+        .collect()
+}
+
+fn duplicate_error(error: &ApiError) -> ApiError {
+    match error {
+        ApiError::VK {
+            error_code,
+            error_msg,
+            request_params,
+        } => ApiError::VK {
+            error_code: *error_code,
+            error_msg: error_msg.clone(),
+            request_params: request_params.clone(),
+        },
+        ApiError::Telegram {
+            error_code,
+            description,
+            parameters,
+        } => ApiError::Telegram {
+            error_code: *error_code,
+            description: description.clone(),
+            parameters: parameters.clone(),
+        },
+        ApiError::Request(HyperRequestError::Timeout(timeout)) => {
+            ApiError::Request(HyperRequestError::Timeout(*timeout))
+        }
+        ApiError::Request(HyperRequestError::ConnectTimeout(timeout)) => {
+            ApiError::Request(HyperRequestError::ConnectTimeout(*timeout))
+        }
+        ApiError::Request(e) => ApiError::Request(HyperRequestError::ResponseError(e.to_string())),
+        ApiError::Json(e) => ApiError::Json(serde_json::Error::custom(e.to_string())),
+        ApiError::Status { status, body } => ApiError::Status {
+            status: *status,
+            body: body.clone(),
+        },
+        ApiError::Upload(error) => ApiError::Upload(error.clone()),
+        ApiError::NotConfigured(platform) => ApiError::NotConfigured(platform.clone()),
+    }
+}
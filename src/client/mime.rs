@@ -0,0 +1,248 @@
+use super::requests::FileType;
+
+// Extension and MIME type pairs, the first extension of a type is the preferred one
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("bmp", "image/bmp"),
+    ("tiff", "image/tiff"),
+    ("tif", "image/tiff"),
+    ("heic", "image/heic"),
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("mov", "video/quicktime"),
+    ("webm", "video/webm"),
+    ("mkv", "video/x-matroska"),
+    ("avi", "video/x-msvideo"),
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("flac", "audio/flac"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("rar", "application/vnd.rar"),
+    ("7z", "application/x-7z-compressed"),
+    ("doc", "application/msword"),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ),
+    ("xls", "application/vnd.ms-excel"),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    ("ppt", "application/vnd.ms-powerpoint"),
+    (
+        "pptx",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    ),
+    ("txt", "text/plain"),
+    ("json", "application/json"),
+];
+
+/// Detects MIME type of common image, video, audio and document formats by magic bytes
+///
+/// # Examples
+///
+/// ```
+/// use vtg::client::mime::sniff;
+///
+/// assert_eq!(sniff(b"\xFF\xD8\xFF\xE0\x00\x10JFIF"), Some("image/jpeg"));
+/// assert_eq!(sniff(b"%PDF-1.7"), Some("application/pdf"));
+/// ```
+pub fn sniff(content: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| content.get(offset..offset + magic.len()) == Some(magic);
+    let mime_type = if at(0, b"\xFF\xD8\xFF") {
+        "image/jpeg"
+    } else if at(0, b"\x89PNG\r\n\x1A\n") {
+        "image/png"
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        "image/gif"
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        "image/webp"
+    } else if at(0, b"RIFF") && at(8, b"WAVE") {
+        "audio/wav"
+    } else if at(0, b"RIFF") && at(8, b"AVI ") {
+        "video/x-msvideo"
+    } else if at(0, b"II*\x00") || at(0, b"MM\x00*") {
+        "image/tiff"
+    } else if at(0, b"BM") && content.len() > 14 {
+        "image/bmp"
+    } else if at(4, b"ftyp") {
+        match content.get(8..12)? {
+            b"heic" | b"heix" | b"mif1" | b"msf1" => "image/heic",
+            b"qt  " => "video/quicktime",
+            b"M4A " => "audio/mp4",
+            _ => "video/mp4",
+        }
+    } else if at(0, b"\x1A\x45\xDF\xA3") {
+        let header = &content[..content.len().min(64)];
+        if header.windows(4).any(|window| window == b"webm") {
+            "video/webm"
+        } else {
+            "video/x-matroska"
+        }
+    } else if at(0, b"ID3")
+        || (content.len() > 1 && content[0] == 0xFF && content[1] & 0xE6 == 0xE2)
+    {
+        // MPEG audio frame sync with layer III
+        "audio/mpeg"
+    } else if at(0, b"OggS") {
+        "audio/ogg"
+    } else if at(0, b"fLaC") {
+        "audio/flac"
+    } else if at(0, b"%PDF-") {
+        "application/pdf"
+    } else if at(0, b"PK\x03\x04") {
+        "application/zip"
+    } else if at(0, b"\x1F\x8B") {
+        "application/gzip"
+    } else if at(0, b"Rar!\x1A\x07") {
+        "application/vnd.rar"
+    } else if at(0, b"7z\xBC\xAF\x27\x1C") {
+        "application/x-7z-compressed"
+    } else if at(0, b"\xD0\xCF\x11\xE0\xA1\xB1\x1A\xE1") {
+        "application/msword"
+    } else {
+        return None;
+    };
+    Some(mime_type)
+}
+
+/// MIME type by file extension, case insensitive
+pub fn from_extension(extension: &str) -> Option<&'static str> {
+    let extension = extension.to_lowercase();
+    MIME_TYPES
+        .iter()
+        .find(|(ext, _)| *ext == extension)
+        .map(|(_, mime_type)| *mime_type)
+}
+
+/// MIME type by extension of the filename
+pub fn from_filename(filename: &str) -> Option<&'static str> {
+    let (_, extension) = filename.rsplit_once('.')?;
+    from_extension(extension)
+}
+
+/// Preferred file extension for MIME type
+pub fn extension(mime_type: &str) -> Option<&'static str> {
+    MIME_TYPES
+        .iter()
+        .find(|(_, mime)| *mime == mime_type)
+        .map(|(ext, _)| *ext)
+}
+
+/// Filename with extension matching the MIME type
+///
+/// Extension is added if missing, and replaced if it's unknown or doesn't match a media type,
+/// so a JPEG served as `photo.php` is named `photo.jpg`. Known document extensions are kept,
+/// since a `.docx` file is detected as a ZIP archive.
+pub fn with_extension(filename: &str, mime_type: &str) -> String {
+    let Some(extension) = extension(mime_type) else {
+        return filename.to_string();
+    };
+    let media = ["image/", "video/", "audio/"]
+        .iter()
+        .any(|prefix| mime_type.starts_with(prefix));
+    match filename.rsplit_once('.') {
+        Some((stem, current)) if !stem.is_empty() => match from_extension(current) {
+            Some(current) if current == mime_type || !media => filename.to_string(),
+            _ => format!("{}.{}", stem, extension),
+        },
+        _ => format!("{}.{}", filename, extension),
+    }
+}
+
+/// File type for MIME type, GIF images are sent as animations
+pub fn file_type(mime_type: &str) -> FileType {
+    match mime_type.split('/').next().unwrap_or("") {
+        _ if mime_type == "image/gif" => FileType::Animation,
+        "image" => FileType::Photo,
+        "video" => FileType::Video,
+        "audio" => FileType::Audio,
+        _ => FileType::Document,
+    }
+}
+
+/// Essence of the `Content-Type` header value, without parameters like `charset`
+pub fn essence(content_type: &str) -> Option<String> {
+    let essence = content_type.split(';').next()?.trim().to_lowercase();
+    (essence.contains('/') && !essence.starts_with('/') && !essence.ends_with('/'))
+        .then_some(essence)
+}
+
+/// Filename from the `Content-Disposition` header value, `filename*` is preferred over `filename`
+pub fn content_disposition_filename(value: &str) -> Option<String> {
+    let mut filename = None;
+    for param in value.split(';').skip(1) {
+        let Some((key, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim().to_lowercase().as_str() {
+            "filename*" => {
+                // RFC 5987: charset'language'percent-encoded
+                if let Some((_, encoded)) = value.split_once('\'')
+                    && let Some((_, encoded)) = encoded.split_once('\'')
+                {
+                    return sanitize_filename(&percent_decode(encoded));
+                }
+            }
+            "filename" if filename.is_none() => {
+                filename = sanitize_filename(value.trim_matches('"'));
+            }
+            _ => {}
+        }
+    }
+    filename
+}
+
+/// Last segment of the URL path
+pub fn url_filename(url: &str) -> Option<String> {
+    let without_query = url.split(['?', '#']).next()?;
+    let path = match without_query.split_once("://") {
+        Some((_, rest)) => rest.split_once('/')?.1,
+        None => without_query,
+    };
+    sanitize_filename(&percent_decode(path.rsplit('/').next()?))
+}
+
+// Drops directories and characters which break multipart headers
+fn sanitize_filename(filename: &str) -> Option<String> {
+    let filename: String = filename
+        .rsplit(['/', '\\'])
+        .next()?
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .collect();
+    let filename = filename.trim();
+    (!filename.is_empty() && filename != "." && filename != "..").then(|| filename.to_string())
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            decoded.push(byte);
+            i += 3;
+            continue;
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::structs::vk::VKGetServer;
+
+/// Storage of longpoll positions, so polling resumes from the same place after restart
+///
+/// Telegram offsets are keyed by bot ID (the part of the token before `:`), VK longpoll state is keyed by group ID.
+/// Setters are called from the pollers after every dispatched batch of updates on the blocking thread pool, so they may do blocking I/O.
+pub trait OffsetStore: Send + Sync + Debug {
+    /// Telegram `offset` to request updates from (last `update_id` + 1)
+    fn tg_offset(&self, bot_id: &str) -> Option<i64>;
+    /// Saves Telegram `offset`
+    fn set_tg_offset(&self, bot_id: &str, offset: i64);
+    /// VK longpoll server, key and `ts` to continue from
+    fn vk_state(&self, group_id: i64) -> Option<VKGetServer>;
+    /// Saves VK longpoll server, key and `ts`
+    fn set_vk_state(&self, group_id: i64, state: &VKGetServer);
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Offsets {
+    tg: HashMap<String, i64>,
+    vk: HashMap<i64, VKGetServer>,
+}
+
+/// Offset store which keeps positions in memory, useful when several clients run in one process
+#[derive(Debug, Default)]
+pub struct MemoryOffsetStore {
+    offsets: Mutex<Offsets>,
+}
+
+impl MemoryOffsetStore {
+    pub fn new() -> Self {
+        MemoryOffsetStore::default()
+    }
+}
+
+impl OffsetStore for MemoryOffsetStore {
+    fn tg_offset(&self, bot_id: &str) -> Option<i64> {
+        self.offsets.lock().unwrap().tg.get(bot_id).copied()
+    }
+    fn set_tg_offset(&self, bot_id: &str, offset: i64) {
+        self.offsets
+            .lock()
+            .unwrap()
+            .tg
+            .insert(bot_id.to_string(), offset);
+    }
+    fn vk_state(&self, group_id: i64) -> Option<VKGetServer> {
+        self.offsets.lock().unwrap().vk.get(&group_id).cloned()
+    }
+    fn set_vk_state(&self, group_id: i64, state: &VKGetServer) {
+        self.offsets
+            .lock()
+            .unwrap()
+            .vk
+            .insert(group_id, state.clone());
+    }
+}
+
+/// Offset store which keeps positions in a JSON file
+///
+/// The file is rewritten on every change through a temporary file, so it is never left half-written
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use vtg::{client::offset_store::FileOffsetStore, structs::config::Config};
+///
+/// let config = Config {
+///    tg_access_token: "TG_ACCESS_TOKEN".to_string(),
+///    offset_store: Some(Arc::new(FileOffsetStore::open("offsets.json").unwrap())),
+///    ..Default::default()
+/// };
+/// ```
+#[derive(Debug)]
+pub struct FileOffsetStore {
+    path: PathBuf,
+    offsets: Mutex<Offsets>,
+}
+
+impl FileOffsetStore {
+    /// Opens the store, reading saved positions if the file exists
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let offsets = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Offsets::default(),
+            Err(err) => return Err(err),
+        };
+        Ok(FileOffsetStore {
+            path,
+            offsets: Mutex::new(offsets),
+        })
+    }
+
+    fn write(&self, offsets: &Offsets) {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let result = serde_json::to_vec(offsets)
+            .map_err(io::Error::from)
+            .and_then(|content| fs::write(&tmp, content))
+            .and_then(|_| fs::rename(&tmp, &self.path));
+        if let Err(err) = result {
+            error!("Unable to save offsets to {:?}: {}", self.path, err);
+        }
+    }
+}
+
+impl OffsetStore for FileOffsetStore {
+    fn tg_offset(&self, bot_id: &str) -> Option<i64> {
+        self.offsets.lock().unwrap().tg.get(bot_id).copied()
+    }
+    fn set_tg_offset(&self, bot_id: &str, offset: i64) {
+        let mut offsets = self.offsets.lock().unwrap();
+        offsets.tg.insert(bot_id.to_string(), offset);
+        self.write(&offsets);
+    }
+    fn vk_state(&self, group_id: i64) -> Option<VKGetServer> {
+        self.offsets.lock().unwrap().vk.get(&group_id).cloned()
+    }
+    fn set_vk_state(&self, group_id: i64, state: &VKGetServer) {
+        let mut offsets = self.offsets.lock().unwrap();
+        offsets.vk.insert(group_id, state.clone());
+        self.write(&offsets);
+    }
+}
@@ -0,0 +1,379 @@
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::Uri;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioIo;
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tower_service::Service;
+
+use crate::structs::config::ProxySettings;
+
+// Limit for the proxy answer to CONNECT, so a broken proxy can't make us read forever
+const MAX_CONNECT_RESPONSE: usize = 8192;
+
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_NO_AUTH: u8 = 0;
+const SOCKS5_USER_PASS: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProxyKind {
+    Http,
+    Socks5,
+}
+
+/// Parsed [`ProxySettings`]
+#[derive(Debug)]
+pub(crate) struct Proxy {
+    kind: ProxyKind,
+    host: String,
+    port: u16,
+    username: String,
+    password: String,
+    no_proxy: Vec<String>,
+}
+
+impl Proxy {
+    pub(crate) fn parse(settings: &ProxySettings) -> io::Result<Self> {
+        let uri: Uri = settings
+            .url
+            .parse()
+            .map_err(|e| invalid_input(format!("proxy URL {:?}: {}", settings.url, e)))?;
+        let (kind, default_port) = match uri.scheme_str() {
+            Some("http") => (ProxyKind::Http, 80),
+            Some("socks5") | Some("socks5h") => (ProxyKind::Socks5, 1080),
+            _ => {
+                return Err(invalid_input(format!(
+                    "proxy URL {:?} must start with http:// or socks5://",
+                    settings.url
+                )));
+            }
+        };
+        let host = uri
+            .host()
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| invalid_input(format!("proxy URL {:?} has no host", settings.url)))?;
+        if kind == ProxyKind::Socks5
+            && (settings.username.len() > 255 || settings.password.len() > 255)
+        {
+            return Err(invalid_input(
+                "SOCKS5 username and password must be at most 255 bytes long",
+            ));
+        }
+        Ok(Proxy {
+            kind,
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: uri.port_u16().unwrap_or(default_port),
+            username: settings.username.clone(),
+            password: settings.password.clone(),
+            no_proxy: settings
+                .no_proxy
+                .iter()
+                .map(|host| {
+                    host.trim()
+                        .trim_start_matches("*.")
+                        .trim_start_matches('.')
+                        .to_lowercase()
+                })
+                .filter(|host| !host.is_empty())
+                .collect(),
+        })
+    }
+
+    /// Whether the host is in the no-proxy list
+    fn bypass(&self, host: &str) -> bool {
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_lowercase();
+        self.no_proxy.iter().any(|entry| {
+            entry == "*"
+                || host == *entry
+                || host
+                    .strip_suffix(entry.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+    }
+}
+
+/// Connector for hyper client which tunnels connections through HTTP CONNECT or SOCKS5 proxy
+///
+/// Without proxy, or for hosts in the no-proxy list, it connects directly.
+/// TLS is established over the tunnel, so the proxy never sees the request content.
+#[derive(Debug, Clone)]
+pub struct ProxyConnector {
+    proxy: Option<Arc<Proxy>>,
+    direct: HttpConnector,
+    connect_timeout: Option<Duration>,
+}
+
+impl ProxyConnector {
+    /// Connector without proxy
+    pub fn direct() -> Self {
+        let mut direct = HttpConnector::new();
+        direct.enforce_http(false);
+        ProxyConnector {
+            proxy: None,
+            direct,
+            connect_timeout: None,
+        }
+    }
+
+    /// Connector through the proxy, fails if the proxy URL is invalid
+    pub fn new(settings: &ProxySettings) -> io::Result<Self> {
+        Ok(ProxyConnector {
+            proxy: Some(Arc::new(Proxy::parse(settings)?)),
+            ..ProxyConnector::direct()
+        })
+    }
+
+    /// Time to establish a connection, including proxy handshake, `None` to wait indefinitely
+    pub fn set_connect_timeout(&mut self, connect_timeout: Option<Duration>) {
+        self.direct.set_connect_timeout(connect_timeout);
+        self.connect_timeout = connect_timeout;
+    }
+}
+
+impl Service<Uri> for ProxyConnector {
+    type Response = TokioIo<TcpStream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.direct.poll_ready(cx).map_err(io::Error::other)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let proxy = self.proxy.clone();
+        let mut direct = self.direct.clone();
+        let connect_timeout = self.connect_timeout;
+        Box::pin(async move {
+            let host = uri
+                .host()
+                .ok_or_else(|| invalid_input("URL has no host"))?
+                .to_string();
+            let proxy = match proxy {
+                Some(proxy) if !proxy.bypass(&host) => proxy,
+                _ => return direct.call(uri).await.map_err(io::Error::other),
+            };
+            let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+                Some("https") => 443,
+                _ => 80,
+            });
+            debug!(
+                "Connecting to {}:{} through proxy {}:{}",
+                host, port, proxy.host, proxy.port
+            );
+            let connect = async {
+                let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port)).await?;
+                stream.set_nodelay(true)?;
+                match proxy.kind {
+                    ProxyKind::Http => http_connect(&mut stream, &proxy, &host, port).await?,
+                    ProxyKind::Socks5 => socks5_connect(&mut stream, &proxy, &host, port).await?,
+                }
+                Ok(TokioIo::new(stream))
+            };
+            match connect_timeout {
+                Some(connect_timeout) => {
+                    timeout(connect_timeout, connect).await.unwrap_or_else(|_| {
+                        Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "proxy connection timed out",
+                        ))
+                    })
+                }
+                None => connect.await,
+            }
+        })
+    }
+}
+
+async fn http_connect(
+    stream: &mut TcpStream,
+    proxy: &Proxy,
+    host: &str,
+    port: u16,
+) -> io::Result<()> {
+    let mut request = format!(
+        "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n",
+        host = host,
+        port = port
+    );
+    if !proxy.username.is_empty() {
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64(format!("{}:{}", proxy.username, proxy.password).as_bytes())
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte, so nothing after the proxy answer is consumed
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_CONNECT_RESPONSE {
+            return Err(io::Error::other("proxy response is too long"));
+        }
+        if stream.read(&mut byte).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "proxy closed connection",
+            ));
+        }
+        response.push(byte[0]);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        Some("407") => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "proxy authentication required",
+        )),
+        _ => Err(io::Error::other(format!(
+            "proxy refused CONNECT: {}",
+            status_line
+        ))),
+    }
+}
+
+async fn socks5_connect(
+    stream: &mut TcpStream,
+    proxy: &Proxy,
+    host: &str,
+    port: u16,
+) -> io::Result<()> {
+    let auth = !proxy.username.is_empty();
+    let greeting: &[u8] = if auth {
+        &[SOCKS5_VERSION, 2, SOCKS5_NO_AUTH, SOCKS5_USER_PASS]
+    } else {
+        &[SOCKS5_VERSION, 1, SOCKS5_NO_AUTH]
+    };
+    stream.write_all(greeting).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(io::Error::other("proxy is not a SOCKS5 proxy"));
+    }
+    match reply[1] {
+        SOCKS5_NO_AUTH => {}
+        SOCKS5_USER_PASS if auth => {
+            let mut request = vec![1, proxy.username.len() as u8];
+            request.extend_from_slice(proxy.username.as_bytes());
+            request.push(proxy.password.len() as u8);
+            request.extend_from_slice(proxy.password.as_bytes());
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "SOCKS5 authentication failed",
+                ));
+            }
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS5 proxy doesn't accept offered authentication methods",
+            ));
+        }
+    }
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut request = vec![SOCKS5_VERSION, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        // Domain is resolved by the proxy
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(invalid_input("host name is too long for SOCKS5"));
+            }
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[1] != 0 {
+        return Err(io::Error::other(format!(
+            "SOCKS5 proxy refused connection: {}",
+            socks5_error(head[1])
+        )));
+    }
+    let address_len = match head[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => return Err(io::Error::other("invalid SOCKS5 proxy response")),
+    };
+    // Bound address and port are not used
+    let mut bound = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+fn socks5_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+fn invalid_input(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.into())
+}
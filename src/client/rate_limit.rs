@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use log::debug;
+use tokio::time::{Instant, sleep};
+
+use crate::structs::config::{Config, RateLimitSettings};
+use crate::structs::context::Platform;
+
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+/// Token bucket which hands out reservations, so waiting callers are served in order
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_second: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket allowing `amount` requests per `period`
+    pub fn new(amount: u32, period: Duration) -> Self {
+        TokenBucket {
+            capacity: amount as f64,
+            tokens: amount as f64,
+            per_second: amount as f64 / period.as_secs_f64(),
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Takes one token and returns how long the caller has to wait before using it
+    pub fn reserve(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.per_second)
+        }
+    }
+
+    fn is_idle(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+lazy_static! {
+    static ref BUCKETS: Mutex<HashMap<String, TokenBucket>> = Mutex::new(HashMap::new());
+}
+
+fn is_tg_message_method(method: &str) -> bool {
+    method.starts_with("send") || method.starts_with("forward") || method.starts_with("copy")
+}
+
+pub(crate) fn buckets_for(
+    settings: &RateLimitSettings,
+    platform: &Platform,
+    method: &str,
+    chat_id: Option<&str>,
+    config: &Config,
+) -> Vec<(String, u32, Duration)> {
+    let second = Duration::from_secs(1);
+    let mut buckets = Vec::new();
+    match platform {
+        Platform::VK => {
+            buckets.push((
+                format!("vk:{}", config.vk_access_token),
+                settings.vk_requests_per_second,
+                second,
+            ));
+        }
+        Platform::Telegram => {
+            if !is_tg_message_method(method) {
+                return buckets;
+            }
+            let token = &config.tg_access_token;
+            buckets.push((
+                format!("tg:{}", token),
+                settings.tg_messages_per_second,
+                second,
+            ));
+            if let Some(chat_id) = chat_id {
+                buckets.push((
+                    format!("tg:{}:{}", token, chat_id),
+                    settings.tg_chat_messages_per_second,
+                    second,
+                ));
+                // Groups, supergroups and channels have negative IDs or a @username
+                let is_group = chat_id.parse::<i64>().map_or(true, |id| id < 0);
+                if is_group {
+                    buckets.push((
+                        format!("tg:{}:{}:minute", token, chat_id),
+                        settings.tg_group_messages_per_minute,
+                        Duration::from_secs(60),
+                    ));
+                }
+            }
+        }
+    }
+    buckets.retain(|(_, amount, _)| *amount > 0);
+    buckets
+}
+
+/// Waits until the request is allowed by the rate limits from `config.rate_limit`
+///
+/// Does nothing if rate limiting is disabled in config
+/// # Arguments
+/// * `platform` - Platform the request is sent to
+/// * `method` - API method name
+/// * `chat_id` - Telegram chat the message is sent to, if any
+/// * `config` - Config to use
+pub async fn wait(platform: Platform, method: &str, chat_id: Option<&str>, config: &Config) {
+    let Some(settings) = &config.rate_limit else {
+        return;
+    };
+    let buckets = buckets_for(settings, &platform, method, chat_id, config);
+    if buckets.is_empty() {
+        return;
+    }
+    let delay = {
+        let now = Instant::now();
+        let mut map = BUCKETS.lock().unwrap();
+        if map.len() > MAX_IDLE_BUCKETS {
+            map.retain(|_, bucket| !bucket.is_idle(now));
+        }
+        buckets
+            .into_iter()
+            .map(|(key, amount, period)| {
+                map.entry(key)
+                    .or_insert_with(|| TokenBucket::new(amount, period))
+                    .reserve(now)
+            })
+            .max()
+            .unwrap_or_default()
+    };
+    if !delay.is_zero() {
+        debug!("[RATE LIMIT] Delaying {} for {:?}", method, delay);
+        sleep(delay).await;
+    }
+}
@@ -1,37 +1,140 @@
-use crate::{client::structs::FastFormSerializer, structs::context::Platform};
-use bytes::Bytes;
-use http_body_util::{BodyExt, Empty, Full};
-use hyper::{
-    header::{CONTENT_LENGTH, CONTENT_TYPE},
-    Method, Request,
+use crate::{
+    client::mime,
+    client::structs::FastFormSerializer,
+    client::transport::TransportError,
+    structs::{
+        config::{Config, DownloadSettings},
+        context::Platform,
+    },
 };
-use hyper_tls::HttpsConnector;
-use hyper_util::{
-    client::legacy::{connect::HttpConnector, Client},
-    rt::TokioExecutor,
+use bytes::{Bytes, BytesMut};
+use http_body_util::BodyExt;
+use hyper::StatusCode;
+use hyper::Uri;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::{
+    CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, HeaderName, LOCATION,
 };
-use lazy_static::lazy_static;
 use log::debug;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use std::borrow::Cow;
-use std::io::{self, Write};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
 
 /// Error enum for HyperRequestError
 /// # Variants
 /// * `RequestError` - Request error
 /// * `ResponseError` - Response error
+/// * `TransportError` - Error of a custom transport
+/// * `Timeout` - Request didn't complete within the configured timeout
+/// * `ConnectTimeout` - Connection wasn't established within the configured timeout, the request wasn't sent
+/// * `StatusError` - Download response has non-2xx status
+/// * `SizeLimitExceeded` - Downloaded file is larger than the limit
+/// * `IoError` - Downloaded file can't be written, or uploaded file can't be read
 #[derive(Debug)]
 pub enum HyperRequestError {
     RequestError(hyper_util::client::legacy::Error),
     ResponseError(String),
+    TransportError(TransportError),
+    Timeout(Duration),
+    ConnectTimeout(Duration),
+    StatusError(StatusCode),
+    SizeLimitExceeded(u64),
+    IoError(io::Error),
 }
-lazy_static! {
-    static ref CLIENT: Client<HttpsConnector<HttpConnector>, Full<Bytes>> =
-        Client::builder(TokioExecutor::new()).build::<_, Full<Bytes>>(HttpsConnector::new());
-    static ref EMPTY_CLIENT: Client<HttpsConnector<HttpConnector>, Empty<Bytes>> =
-        Client::builder(TokioExecutor::new()).build::<_, Empty<Bytes>>(HttpsConnector::new());
+
+impl HyperRequestError {
+    /// Whether the request failed because of a timeout
+    pub fn is_timeout(&self) -> bool {
+        matches!(
+            self,
+            HyperRequestError::Timeout(_) | HyperRequestError::ConnectTimeout(_)
+        )
+    }
+
+    /// Whether the connection failed, so the request wasn't sent and can be safely repeated
+    pub fn is_connect(&self) -> bool {
+        if let HyperRequestError::ConnectTimeout(_) = self {
+            return true;
+        }
+        let mut source: Option<&(dyn Error + 'static)> = match self {
+            HyperRequestError::RequestError(e) => Some(e),
+            HyperRequestError::TransportError(e) => Some(e.as_ref()),
+            _ => None,
+        };
+        while let Some(err) = source {
+            if err
+                .downcast_ref::<hyper_util::client::legacy::Error>()
+                .is_some_and(|err| err.is_connect())
+            {
+                return true;
+            }
+            source = err.source();
+        }
+        false
+    }
+
+    // Connect timeout of the connector comes as an I/O error somewhere in the error chain
+    fn is_connect_timeout(&self) -> bool {
+        let mut source: Option<&(dyn Error + 'static)> = match self {
+            HyperRequestError::RequestError(e) => Some(e),
+            HyperRequestError::TransportError(e) => Some(e.as_ref()),
+            _ => None,
+        };
+        while let Some(err) = source {
+            if err
+                .downcast_ref::<io::Error>()
+                .is_some_and(|err| err.kind() == io::ErrorKind::TimedOut)
+            {
+                return true;
+            }
+            source = err.source();
+        }
+        false
+    }
 }
+
+impl fmt::Display for HyperRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HyperRequestError::RequestError(e) => write!(f, "Request error: {}", e),
+            HyperRequestError::ResponseError(e) => write!(f, "Response error: {}", e),
+            HyperRequestError::TransportError(e) => write!(f, "Transport error: {}", e),
+            HyperRequestError::Timeout(timeout) => {
+                write!(f, "Request timed out after {:?}", timeout)
+            }
+            HyperRequestError::ConnectTimeout(timeout) => {
+                write!(f, "Connection timed out after {:?}", timeout)
+            }
+            HyperRequestError::StatusError(status) => write!(f, "Unexpected status: {}", status),
+            HyperRequestError::SizeLimitExceeded(limit) => {
+                write!(f, "File is larger than {} bytes", limit)
+            }
+            HyperRequestError::IoError(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HyperRequestError {}
+
+impl From<TransportError> for HyperRequestError {
+    fn from(e: TransportError) -> Self {
+        match e.downcast::<hyper_util::client::legacy::Error>() {
+            Ok(e) => HyperRequestError::RequestError(*e),
+            Err(e) => HyperRequestError::TransportError(e),
+        }
+    }
+}
+
 /// Sends a POST request with the specified access token and body.
 /// # Returns
 ///
@@ -40,81 +143,425 @@ pub async fn request(
     url: &str,
     access_token: &str,
     body: Vec<(Cow<'_, str>, Cow<'_, str>)>,
+    platform: Platform,
+    config: &Config,
 ) -> Result<String, HyperRequestError> {
+    request_with_status(url, access_token, body, platform, config)
+        .await
+        .map(|(_, body)| body)
+}
+/// Sends a POST request with the specified access token and body, limited by `config.timeouts.request`.
+/// # Returns
+///
+/// Returns the response status code and the response body as a string.
+pub async fn request_with_status(
+    url: &str,
+    access_token: &str,
+    body: Vec<(Cow<'_, str>, Cow<'_, str>)>,
+    platform: Platform,
+    config: &Config,
+) -> Result<(StatusCode, String), HyperRequestError> {
+    let timeout = config.timeouts.request;
+    request_with_timeout(url, access_token, body, platform, config, timeout).await
+}
+/// Sends a POST request with the specified access token and body, limited by `timeout`.
+/// # Returns
+///
+/// Returns the response status code and the response body as a string.
+pub async fn request_with_timeout(
+    url: &str,
+    access_token: &str,
+    body: Vec<(Cow<'_, str>, Cow<'_, str>)>,
+    platform: Platform,
+    config: &Config,
+    timeout: Duration,
+) -> Result<(StatusCode, String), HyperRequestError> {
     let mut serializer = FastFormSerializer::new(&body);
     let form_body = serializer.extend_pairs(&body).finish();
     debug!("Request body: {}", form_body);
-    let req = Request::builder()
-        .method(Method::POST)
-        .uri(url)
-        .header("Authorization", format!("Bearer {}", access_token))
-        .header(CONTENT_LENGTH, form_body.len())
-        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
-        .body(Full::from(form_body))
-        .unwrap();
-    let res = CLIENT
-        .request(req)
-        .await
-        .map_err(HyperRequestError::RequestError)?;
-    let body = res.collect().await.unwrap().to_bytes();
-    String::from_utf8(body.to_vec()).map_err(|e| HyperRequestError::ResponseError(e.to_string()))
+    with_timeout(timeout, config, async {
+        let res = config
+            .transport(&platform)
+            .post_form(url, access_token, form_body)
+            .await?;
+        let status = res.status();
+        let body = res
+            .collect()
+            .await
+            .map_err(|e| HyperRequestError::ResponseError(e.to_string()))?
+            .to_bytes();
+        let body = String::from_utf8(body.to_vec())
+            .map_err(|e| HyperRequestError::ResponseError(e.to_string()))?;
+        Ok((status, body))
+    })
+    .await
+}
+
+// Whole exchange, including reading the body, must complete in time
+async fn with_timeout<T>(
+    timeout: Duration,
+    config: &Config,
+    future: impl Future<Output = Result<T, HyperRequestError>>,
+) -> Result<T, HyperRequestError> {
+    match tokio::time::timeout(timeout, future).await {
+        Ok(Err(e)) if e.is_connect_timeout() => {
+            Err(HyperRequestError::ConnectTimeout(config.timeouts.connect))
+        }
+        Ok(result) => result,
+        Err(_) => Err(HyperRequestError::Timeout(timeout)),
+    }
 }
-/// Sends a GET request to the specified URL, download files from it.
+/// Sends a GET request to the specified URL, download files from it, limited by `config.download` and `config.timeouts.download`.
 /// # Returns
 ///
 /// Returns a File struct with the file content and type.
-pub async fn get_file(url: &str) -> Result<File, HyperRequestError> {
-    let req = Request::builder()
-        .method(Method::GET)
-        .uri(url)
-        .body(Empty::new())
-        .unwrap();
-    let res = EMPTY_CLIENT
-        .request(req)
-        .await
-        .map_err(HyperRequestError::RequestError)?;
-
-    let content_type = res
-        .headers()
-        .get("Content-Type")
-        .and_then(|value| value.to_str().ok());
-    let mut filename = String::new();
-    let ftype = content_type
-        .map(|value| {
-            let mut parts = value.split('/');
-            let media_type = parts.next().unwrap_or("");
-            let subtype = parts.next().unwrap_or("");
-            filename = format!("something.{}", subtype);
-            match (media_type, subtype) {
-                ("image", _) => FileType::Photo,
-                ("video", _) => FileType::Video,
-                ("audio", _) => FileType::Audio,
-                _ => FileType::Document,
-            }
-        })
-        .unwrap_or(FileType::Other);
+pub async fn get_file(
+    url: &str,
+    platform: Platform,
+    config: &Config,
+) -> Result<File, HyperRequestError> {
+    let mut content = Vec::new();
+    let download = download(url, &mut content, platform, config, &config.download).await?;
 
-    let bytes = res.collect().await.unwrap().to_bytes();
+    let mut filename = download
+        .content_disposition
+        .as_deref()
+        .and_then(mime::content_disposition_filename)
+        .or_else(|| mime::url_filename(&download.url))
+        .unwrap_or_else(|| "file".to_string());
+    // Content is trusted over the header, generic header is ignored
+    let mime_type = mime::sniff(&content)
+        .map(str::to_string)
+        .or_else(|| {
+            download
+                .content_type
+                .as_deref()
+                .and_then(mime::essence)
+                .filter(|essence| !essence.ends_with("/octet-stream"))
+        })
+        .or_else(|| mime::from_filename(&filename).map(str::to_string));
+    let ftype = match &mime_type {
+        Some(mime_type) => {
+            filename = mime::with_extension(&filename, mime_type);
+            mime::file_type(mime_type)
+        }
+        None => FileType::Other,
+    };
+    debug!(
+        "[DOWNLOAD] {} is {} ({:?})",
+        download.url, filename, mime_type
+    );
 
     Ok(File {
         filename,
-        content: bytes.to_vec(),
+        mime_type,
+        content: FileContent::Bytes(content.into()),
         ftype,
     })
 }
 
+/// Downloaded file info
+/// # Fields
+/// * `url` - URL the file was downloaded from, after redirects
+/// * `content_type` - `Content-Type` of the response
+/// * `content_disposition` - `Content-Disposition` of the response
+/// * `size` - File size in bytes
+#[derive(Debug, Clone)]
+pub struct Download {
+    pub url: String,
+    pub content_type: Option<String>,
+    pub content_disposition: Option<String>,
+    pub size: u64,
+}
+
+/// Downloads a file to the writer chunk by chunk, limited by `settings` and `config.timeouts.download`.
+///
+/// Redirects are followed, non-2xx responses fail with [`HyperRequestError::StatusError`],
+/// files larger than `settings.max_size` fail with [`HyperRequestError::SizeLimitExceeded`].
+/// # Returns
+///
+/// Returns downloaded file info.
+pub async fn download<W: AsyncWrite + Unpin>(
+    url: &str,
+    writer: &mut W,
+    platform: Platform,
+    config: &Config,
+    settings: &DownloadSettings,
+) -> Result<Download, HyperRequestError> {
+    with_timeout(config.timeouts.download, config, async {
+        let transport = config.transport(&platform);
+        let mut url = url.to_string();
+        let mut redirects = 0;
+        let res = loop {
+            let res = transport.get(&url).await?;
+            let location = res
+                .headers()
+                .get(LOCATION)
+                .and_then(|value| value.to_str().ok());
+            let location = match location {
+                Some(location) if res.status().is_redirection() => location,
+                _ => break res,
+            };
+            if redirects >= settings.max_redirects {
+                return Err(HyperRequestError::ResponseError(format!(
+                    "Too many redirects, the last one to {}",
+                    location
+                )));
+            }
+            url = resolve_redirect(&url, location).ok_or_else(|| {
+                HyperRequestError::ResponseError(format!("Invalid redirect to {}", location))
+            })?;
+            debug!("[DOWNLOAD] Redirected to {}", url);
+            redirects += 1;
+        };
+        if !res.status().is_success() {
+            return Err(HyperRequestError::StatusError(res.status()));
+        }
+        let total = res
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if total.is_some_and(|total| total > settings.max_size) {
+            return Err(HyperRequestError::SizeLimitExceeded(settings.max_size));
+        }
+        let content_type = header_value(res.headers(), CONTENT_TYPE);
+        let content_disposition = header_value(res.headers(), CONTENT_DISPOSITION);
+
+        let mut body = res.into_body();
+        let mut size = 0;
+        while let Some(frame) = body.frame().await {
+            let Ok(chunk) = frame?.into_data() else {
+                continue;
+            };
+            size += chunk.len() as u64;
+            if size > settings.max_size {
+                return Err(HyperRequestError::SizeLimitExceeded(settings.max_size));
+            }
+            writer
+                .write_all(&chunk)
+                .await
+                .map_err(HyperRequestError::IoError)?;
+            if let Some(progress) = &settings.progress {
+                progress(size, total);
+            }
+        }
+        writer.flush().await.map_err(HyperRequestError::IoError)?;
+        Ok(Download {
+            url,
+            content_type,
+            content_disposition,
+            size,
+        })
+    })
+    .await
+}
+
+/// Downloads a file to the path, like [`download`]. Partially downloaded file is removed on error.
+/// # Returns
+///
+/// Returns downloaded file info.
+pub async fn download_to_path(
+    url: &str,
+    path: impl AsRef<Path>,
+    platform: Platform,
+    config: &Config,
+    settings: &DownloadSettings,
+) -> Result<Download, HyperRequestError> {
+    let path = path.as_ref();
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .map_err(HyperRequestError::IoError)?;
+    let result = download(url, &mut file, platform, config, settings).await;
+    if result.is_err() {
+        drop(file);
+        if let Err(e) = tokio::fs::remove_file(path).await {
+            debug!("[DOWNLOAD] Unable to remove {:?}: {}", path, e);
+        }
+    }
+    result
+}
+
+fn header_value(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+// Location may be absolute, scheme-relative or relative to the current URL
+fn resolve_redirect(base: &str, location: &str) -> Option<String> {
+    let base: Uri = base.parse().ok()?;
+    let scheme = base.scheme_str()?;
+    if let Some(rest) = location.strip_prefix("//") {
+        return Some(format!("{}://{}", scheme, rest));
+    }
+    if location.contains("://") {
+        return location.parse::<Uri>().ok().map(|_| location.to_string());
+    }
+    let authority = base.authority()?.as_str();
+    if location.starts_with('/') {
+        return Some(format!("{}://{}{}", scheme, authority, location));
+    }
+    let dir = base.path().rsplit_once('/').map_or("", |(dir, _)| dir);
+    Some(format!("{}://{}{}/{}", scheme, authority, dir, location))
+}
+
 /// File struct with the file content and type.
 /// # Fields
 /// * `filename` - Name of the file, please use real name like cat.jpg or video.mp4
-/// * `content` - File content
+/// * `mime_type` - MIME type, sent as `Content-Type` of the file part if set
+/// * `content` - File content, in memory or read while uploading, see [`FileContent`]
 /// * `ftype` - File type
+///
+/// Constructors detect MIME type by content (for files in memory) or by filename extension.
+///
+/// # Examples
+///
+/// ```no_run
+/// use vtg::client::requests::{File, FileType};
+///
+/// # async fn example() {
+/// let photo = File::from_bytes("cat.jpg", tokio::fs::read("cat.jpg").await.unwrap(), FileType::Photo);
+/// // Read from disk while uploading
+/// let video = File::from_path("video.mp4", FileType::Video);
+/// let stream = tokio::fs::File::open("song.mp3").await.unwrap();
+/// let audio = File::from_reader("song.mp3", stream, None, FileType::Audio);
+/// # }
+/// ```
 #[derive(Clone, Debug)]
 pub struct File {
     pub filename: String,
-    pub content: Vec<u8>,
+    pub mime_type: Option<String>,
+    pub content: FileContent,
     pub ftype: FileType,
 }
 
+impl File {
+    /// File with the content in memory
+    pub fn from_bytes(
+        filename: impl Into<String>,
+        content: impl Into<Bytes>,
+        ftype: FileType,
+    ) -> Self {
+        let filename = filename.into();
+        let content = content.into();
+        File {
+            mime_type: mime::sniff(&content)
+                .or_else(|| mime::from_filename(&filename))
+                .map(str::to_string),
+            filename,
+            content: FileContent::Bytes(content),
+            ftype,
+        }
+    }
+
+    /// File on disk, named by the last path component
+    pub fn from_path(path: impl Into<PathBuf>, ftype: FileType) -> Self {
+        let path = path.into();
+        let filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        File {
+            mime_type: mime::from_filename(&filename).map(str::to_string),
+            filename,
+            content: FileContent::Path(path),
+            ftype,
+        }
+    }
+
+    /// File read from the async reader, `len` is the exact content length if known
+    pub fn from_reader(
+        filename: impl Into<String>,
+        reader: impl AsyncRead + Send + 'static,
+        len: Option<u64>,
+        ftype: FileType,
+    ) -> Self {
+        let filename = filename.into();
+        File {
+            mime_type: mime::from_filename(&filename).map(str::to_string),
+            filename,
+            content: FileContent::Reader(FileReader::new(reader, len)),
+            ftype,
+        }
+    }
+}
+
+/// Content of a [`File`]
+/// # Variants
+/// * `Bytes` - Content in memory
+/// * `Path` - File on disk, read chunk by chunk while uploading
+/// * `Reader` - Async reader, read chunk by chunk while uploading
+#[derive(Clone, Debug)]
+pub enum FileContent {
+    Bytes(Bytes),
+    Path(PathBuf),
+    Reader(FileReader),
+}
+
+impl From<Vec<u8>> for FileContent {
+    fn from(content: Vec<u8>) -> Self {
+        FileContent::Bytes(content.into())
+    }
+}
+
+impl From<Bytes> for FileContent {
+    fn from(content: Bytes) -> Self {
+        FileContent::Bytes(content)
+    }
+}
+
+impl From<PathBuf> for FileContent {
+    fn from(path: PathBuf) -> Self {
+        FileContent::Path(path)
+    }
+}
+
+type BoxReader = Pin<Box<dyn AsyncRead + Send>>;
+
+/// Async reader of a file content
+///
+/// The reader can be read only once, clones of the [`File`] share it,
+/// so uploading the same file twice fails.
+#[derive(Clone)]
+pub struct FileReader {
+    reader: Arc<Mutex<Option<BoxReader>>>,
+    len: Option<u64>,
+}
+
+impl FileReader {
+    /// Reader with the exact content length, if known. Without length the file is uploaded with chunked encoding
+    pub fn new(reader: impl AsyncRead + Send + 'static, len: Option<u64>) -> Self {
+        FileReader {
+            reader: Arc::new(Mutex::new(Some(Box::pin(reader)))),
+            len,
+        }
+    }
+
+    /// Content length, if known
+    pub fn len(&self) -> Option<u64> {
+        self.len
+    }
+
+    /// Whether the content is known to be empty
+    pub fn is_empty(&self) -> bool {
+        self.len == Some(0)
+    }
+
+    fn take(&self) -> Option<BoxReader> {
+        self.reader.lock().unwrap().take()
+    }
+}
+
+impl fmt::Debug for FileReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileReader")
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
 /// File type enum.
 /// # Variants
 /// * `Photo` - Photo file
@@ -154,7 +601,7 @@ impl fmt::Display for FileType {
         write!(f, "{}", s)
     }
 }
-/// Sends a POST request with the specified files data to VK or Telegram servers.
+/// Sends a POST request with the specified files data to VK or Telegram servers, limited by `config.timeouts.upload`.
 ///
 /// # Returns
 ///
@@ -164,14 +611,14 @@ pub async fn files_request(
     files: &[File],
     data: Option<Vec<(&str, &str)>>,
     platform: Platform,
-) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    config: &Config,
+) -> Result<String, HyperRequestError> {
     debug!("Request url: {}", url);
     let boundary: String = rand::thread_rng()
         .sample_iter(&Alphanumeric)
         .take(30)
         .map(char::from)
         .collect();
-    let mut body = vec![];
     let query = match data {
         Some(data) => {
             let mut serializer = FastFormSerializer::new_vec(&data);
@@ -179,6 +626,7 @@ pub async fn files_request(
         }
         None => String::new(),
     };
+    let mut body = MultipartBody::default();
     for (index, f) in files.iter().enumerate() {
         let mut name: String = f.ftype.to_string();
         if platform == Platform::VK {
@@ -187,53 +635,162 @@ pub async fn files_request(
         if index != 0 {
             name = name + &index.to_string();
         }
-        let mut is_last: bool = false;
-        if index == files.len() - 1 {
-            is_last = true;
+        let mut header = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n",
+            boundary,
+            name.replace('_', "").to_lowercase(),
+            f.filename
+        );
+        if let Some(mime_type) = &f.mime_type {
+            header.push_str(&format!("Content-Type: {}\r\n", mime_type));
         }
-        let file = file_data(
-            f.clone(),
-            &boundary,
-            &name.replace('_', "").to_lowercase(),
-            is_last,
-        )
-        .expect("Error while reading file");
-        body.extend(file);
-    }
-    body.extend_from_slice(b"--");
-    debug!("[FILE] Request body len: {}", body.len());
-
-    let req = Request::builder()
-        .method(Method::POST)
-        .uri(url.to_owned() + &query)
-        .header(
-            CONTENT_TYPE,
-            format!("multipart/form-data; boundary={}", boundary),
-        )
-        .body(body.into())
-        .unwrap();
-    let res = CLIENT.request(req).await?;
-    let body = res.collect().await.unwrap().to_bytes();
-    let body_str = String::from_utf8(body.to_vec())?;
+        header.push_str("\r\n");
+        body.push_bytes(header);
+        match &f.content {
+            FileContent::Bytes(content) => body.push_bytes(content.clone()),
+            FileContent::Path(path) => {
+                let file = tokio::fs::File::open(path)
+                    .await
+                    .map_err(HyperRequestError::IoError)?;
+                let len = file
+                    .metadata()
+                    .await
+                    .map_err(HyperRequestError::IoError)?
+                    .len();
+                body.push_reader(Box::pin(file), Some(len));
+            }
+            FileContent::Reader(reader) => {
+                let stream = reader.take().ok_or_else(|| {
+                    HyperRequestError::IoError(io::Error::other(format!(
+                        "file {} was already uploaded",
+                        f.filename
+                    )))
+                })?;
+                body.push_reader(stream, reader.len());
+            }
+        }
+        body.push_bytes("\r\n");
+    }
+    body.push_bytes(format!("--{}--\r\n", boundary));
+    debug!("[FILE] Request body len: {:?}", body.len);
+
+    let body_str = with_timeout(config.timeouts.upload, config, async {
+        let res = config
+            .transport(&platform)
+            .post_multipart(&(url.to_owned() + &query), &boundary, body.boxed_unsync())
+            .await?;
+        let body = res.collect().await?.to_bytes();
+        String::from_utf8(body.to_vec())
+            .map_err(|e| HyperRequestError::ResponseError(e.to_string()))
+    })
+    .await?;
     debug!("Response body: {}", body_str);
 
     Ok(body_str)
 }
 
-fn file_data(file: File, boundary: &str, name: &str, is_last: bool) -> io::Result<Vec<u8>> {
-    let mut data = Vec::new();
-    let filename = file.filename;
-    write!(data, "--{}\r\n", boundary)?;
-    write!(
-        data,
-        "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n",
-        name, filename
-    )?;
-    write!(data, "\r\n")?;
-    data.write_all(&file.content)?;
-    write!(data, "\r\n")?;
-    if is_last {
-        write!(data, "\r\n--{}", boundary)?;
-    }
-    Ok(data)
+// Size of chunks read from files while uploading
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+enum MultipartPart {
+    Bytes(Bytes),
+    Reader(BoxReader),
+}
+
+/// Streaming multipart body, files are read chunk by chunk as hyper sends them
+///
+/// If lengths of all parts are known, exact size is reported, so the request is sent with `Content-Length`
+struct MultipartBody {
+    parts: VecDeque<MultipartPart>,
+    len: Option<u64>,
+    sent: u64,
+    buf: BytesMut,
+}
+
+impl Default for MultipartBody {
+    fn default() -> Self {
+        MultipartBody {
+            parts: VecDeque::new(),
+            len: Some(0),
+            sent: 0,
+            buf: BytesMut::new(),
+        }
+    }
+}
+
+impl MultipartBody {
+    fn push_bytes(&mut self, bytes: impl Into<Bytes>) {
+        let bytes = bytes.into();
+        self.len = self.len.map(|len| len + bytes.len() as u64);
+        self.parts.push_back(MultipartPart::Bytes(bytes));
+    }
+
+    fn push_reader(&mut self, reader: BoxReader, len: Option<u64>) {
+        self.len = self.len.zip(len).map(|(total, len)| total + len);
+        self.parts.push_back(MultipartPart::Reader(reader));
+    }
+
+    fn length_error(&self) -> Option<TransportError> {
+        match self.len {
+            Some(len) if self.sent > len => Some("file is longer than its declared length".into()),
+            Some(len) if self.parts.is_empty() && self.sent < len => {
+                Some("file is shorter than its declared length".into())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Body for MultipartBody {
+    type Data = Bytes;
+    type Error = TransportError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            let chunk = match this.parts.front_mut() {
+                None => return Poll::Ready(None),
+                Some(MultipartPart::Bytes(bytes)) => std::mem::take(bytes),
+                Some(MultipartPart::Reader(reader)) => {
+                    this.buf.resize(UPLOAD_CHUNK_SIZE, 0);
+                    let mut read_buf = ReadBuf::new(&mut this.buf);
+                    match reader.as_mut().poll_read(cx, &mut read_buf) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                        Poll::Ready(Ok(())) => {
+                            let read = read_buf.filled().len();
+                            this.buf.truncate(read);
+                            this.buf.split().freeze()
+                        }
+                    }
+                }
+            };
+            // Readers are done on empty read, bytes parts are taken at once
+            if chunk.is_empty() || matches!(this.parts.front(), Some(MultipartPart::Bytes(_))) {
+                this.parts.pop_front();
+            }
+            this.sent += chunk.len() as u64;
+            if let Some(err) = this.length_error() {
+                this.parts.clear();
+                return Poll::Ready(Some(Err(err)));
+            }
+            if !chunk.is_empty() {
+                return Poll::Ready(Some(Ok(Frame::data(chunk))));
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.len {
+            Some(len) => SizeHint::with_exact(len.saturating_sub(self.sent)),
+            None => SizeHint::default(),
+        }
+    }
 }
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Debug;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Method, Request, Response};
+use hyper_tls::HttpsConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use lazy_static::lazy_static;
+
+use super::proxy::ProxyConnector;
+use crate::structs::config::ProxySettings;
+
+/// Error returned by transports
+pub type TransportError = Box<dyn Error + Send + Sync>;
+
+/// Streaming request and response body used by transports
+pub type TransportBody = UnsyncBoxBody<Bytes, TransportError>;
+
+/// Future returned by transport methods
+pub type TransportFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Response<TransportBody>, TransportError>> + Send + 'a>>;
+
+/// Body with the whole content in memory
+pub fn full_body(content: impl Into<Bytes>) -> TransportBody {
+    Full::new(content.into())
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+/// Empty body
+pub fn empty_body() -> TransportBody {
+    Empty::new().map_err(|never| match never {}).boxed_unsync()
+}
+
+/// HTTP transport used for all requests to VK and Telegram
+///
+/// Only [`request`](Transport::request) has to be implemented, form, multipart and GET requests are built on top of it,
+/// so a transport wrapping [`HyperTransport`] can add headers, record or fake requests.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use vtg::client::transport::{HyperTransport, Transport, TransportBody, TransportFuture};
+/// use vtg::structs::config::Config;
+///
+/// #[derive(Debug)]
+/// struct WithHeader(HyperTransport);
+///
+/// impl Transport for WithHeader {
+///     fn request(&self, mut req: hyper::Request<TransportBody>) -> TransportFuture<'_> {
+///         req.headers_mut().insert("X-Egress", "bots".parse().unwrap());
+///         self.0.request(req)
+///     }
+/// }
+///
+/// let config = Config {
+///     tg_access_token: "TG_ACCESS_TOKEN".to_string(),
+///     transport: Some(Arc::new(WithHeader(HyperTransport::new()))),
+///     ..Default::default()
+/// };
+/// ```
+pub trait Transport: Send + Sync + Debug {
+    /// Sends the request and returns the response with streaming body
+    fn request(&self, req: Request<TransportBody>) -> TransportFuture<'_>;
+
+    /// Sends `application/x-www-form-urlencoded` POST request
+    fn post_form(&self, url: &str, access_token: &str, form: String) -> TransportFuture<'_> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header("Authorization", format!("Bearer {}", access_token))
+            .header(CONTENT_LENGTH, form.len())
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(full_body(form));
+        match req {
+            Ok(req) => self.request(req),
+            Err(err) => Box::pin(async move { Err(err.into()) }),
+        }
+    }
+
+    /// Sends `multipart/form-data` POST request, `body` must be already encoded with `boundary`
+    fn post_multipart(
+        &self,
+        url: &str,
+        boundary: &str,
+        body: TransportBody,
+    ) -> TransportFuture<'_> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(body);
+        match req {
+            Ok(req) => self.request(req),
+            Err(err) => Box::pin(async move { Err(err.into()) }),
+        }
+    }
+
+    /// Sends GET request
+    fn get(&self, url: &str) -> TransportFuture<'_> {
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(url)
+            .body(empty_body());
+        match req {
+            Ok(req) => self.request(req),
+            Err(err) => Box::pin(async move { Err(err.into()) }),
+        }
+    }
+}
+
+/// Default transport, hyper client with TLS
+#[derive(Debug, Clone)]
+pub struct HyperTransport {
+    client: Client<HttpsConnector<ProxyConnector>, TransportBody>,
+}
+
+impl HyperTransport {
+    pub fn new() -> Self {
+        HyperTransport::with_connector(ProxyConnector::direct())
+    }
+
+    /// Transport which connects through the proxy, fails if the proxy URL is invalid
+    pub fn with_proxy(proxy: &ProxySettings) -> io::Result<Self> {
+        Ok(HyperTransport::with_connector(ProxyConnector::new(proxy)?))
+    }
+
+    /// Transport with the connector, to set connect timeout or proxy
+    pub fn with_connector(connector: ProxyConnector) -> Self {
+        HyperTransport {
+            client: Client::builder(TokioExecutor::new())
+                .build(HttpsConnector::new_with_connector(connector)),
+        }
+    }
+}
+
+impl Default for HyperTransport {
+    fn default() -> Self {
+        HyperTransport::new()
+    }
+}
+
+impl Transport for HyperTransport {
+    fn request(&self, req: Request<TransportBody>) -> TransportFuture<'_> {
+        let response = self.client.request(req);
+        Box::pin(async move {
+            let response = response.await?;
+            Ok(response.map(|body| body.map_err(TransportError::from).boxed_unsync()))
+        })
+    }
+}
+
+// Transports are shared by proxy settings and connect timeout
+type TransportKey = (Option<ProxySettings>, Duration);
+
+lazy_static! {
+    static ref TRANSPORTS: Mutex<HashMap<TransportKey, Arc<dyn Transport>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Hyper transport with the proxy and connect timeout, shared by all configs with the same settings to reuse connections
+///
+/// Panics if the proxy settings are invalid, see [`Config::check`](crate::structs::config::Config::check)
+pub fn shared_transport(
+    proxy: Option<&ProxySettings>,
+    connect_timeout: Duration,
+) -> Arc<dyn Transport> {
+    let key = (proxy.cloned(), connect_timeout);
+    let mut transports = TRANSPORTS.lock().unwrap();
+    if let Some(transport) = transports.get(&key) {
+        return Arc::clone(transport);
+    }
+    let mut connector = match proxy.map(ProxyConnector::new) {
+        Some(Ok(connector)) => connector,
+        Some(Err(err)) => panic!("Invalid proxy settings: {}", err),
+        None => ProxyConnector::direct(),
+    };
+    connector.set_connect_timeout(Some(connect_timeout));
+    let transport: Arc<dyn Transport> = Arc::new(HyperTransport::with_connector(connector));
+    transports.insert(key, Arc::clone(&transport));
+    transport
+}
@@ -0,0 +1,274 @@
+use log::{debug, log_enabled, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, timeout_at};
+
+use crate::structs::config::{Config, DedupSettings, DispatchMode, QueueOverflow};
+use crate::structs::context::{Platform, UnifyedContext};
+use crate::structs::middleware::MiddlewareChain;
+
+#[derive(Debug, Default)]
+struct QueueState {
+    updates: VecDeque<UnifyedContext>,
+    closed: bool,
+}
+
+/// Bounded update queue with configurable overflow behavior
+#[derive(Debug)]
+pub(crate) struct UpdateQueue {
+    state: Mutex<QueueState>,
+    not_empty: Notify,
+    not_full: Notify,
+    capacity: usize,
+    overflow: QueueOverflow,
+}
+
+impl UpdateQueue {
+    pub(crate) fn new(capacity: usize, overflow: QueueOverflow) -> Self {
+        UpdateQueue {
+            state: Mutex::new(QueueState::default()),
+            not_empty: Notify::new(),
+            not_full: Notify::new(),
+            capacity: capacity.max(1),
+            overflow,
+        }
+    }
+
+    pub(crate) async fn push(&self, update: UnifyedContext) -> Result<(), UnifyedContext> {
+        loop {
+            let not_full = self.not_full.notified();
+            tokio::pin!(not_full);
+            not_full.as_mut().enable();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return Err(update);
+                }
+                if state.updates.len() < self.capacity {
+                    state.updates.push_back(update);
+                    drop(state);
+                    self.not_empty.notify_one();
+                    return Ok(());
+                }
+                match self.overflow {
+                    QueueOverflow::Block => {}
+                    QueueOverflow::DropOldest => {
+                        state.updates.pop_front();
+                        state.updates.push_back(update);
+                        warn!("Update queue is full, dropped the oldest update");
+                        return Ok(());
+                    }
+                    QueueOverflow::DropNewest => {
+                        warn!("Update queue is full, dropped the newest update");
+                        return Ok(());
+                    }
+                }
+            }
+            not_full.await;
+        }
+    }
+
+    pub(crate) async fn pop(&self) -> Option<UnifyedContext> {
+        loop {
+            let not_empty = self.not_empty.notified();
+            tokio::pin!(not_empty);
+            not_empty.as_mut().enable();
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(update) = state.updates.pop_front() {
+                    drop(state);
+                    self.not_full.notify_one();
+                    return Some(update);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            not_empty.await;
+        }
+    }
+
+    pub(crate) fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_waiters();
+        self.not_full.notify_waiters();
+    }
+}
+
+/// Recently seen update IDs, bounded by count and age
+#[derive(Debug)]
+pub(crate) struct DedupCache {
+    capacity: usize,
+    ttl: Duration,
+    seen: HashMap<String, Instant>,
+    order: VecDeque<(String, Instant)>,
+}
+
+impl DedupCache {
+    pub(crate) fn new(settings: &DedupSettings) -> Self {
+        DedupCache {
+            capacity: settings.capacity.max(1),
+            ttl: settings.ttl,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Remembers the ID and returns whether it was already seen
+    pub(crate) fn is_duplicate(&mut self, id: &str, now: Instant) -> bool {
+        while let Some((oldest, seen_at)) = self.order.front() {
+            if self.order.len() < self.capacity && now.duration_since(*seen_at) < self.ttl {
+                break;
+            }
+            self.seen.remove(oldest);
+            self.order.pop_front();
+        }
+        if self.seen.contains_key(id) {
+            return true;
+        }
+        self.seen.insert(id.to_string(), now);
+        self.order.push_back((id.to_string(), now));
+        false
+    }
+}
+
+/// Handle to pass updates to the [`Dispatcher`] workers
+#[derive(Debug, Clone)]
+pub struct UpdateSender {
+    queues: Arc<Vec<UpdateQueue>>,
+    dedup: Option<Arc<Mutex<DedupCache>>>,
+}
+
+impl UpdateSender {
+    /// Puts update to the queue, applying overflow policy if the queue is full
+    ///
+    /// Returns the update back if the dispatcher is shut down
+    pub async fn send(&self, update: UnifyedContext) -> Result<(), UnifyedContext> {
+        self.queues[queue_index(&update, self.queues.len())]
+            .push(update)
+            .await
+    }
+
+    /// Like [`send`](UpdateSender::send), but drops the update if an update with the same `id` was sent recently
+    ///
+    /// Updates without `id` are always sent, as well as all updates if deduplication is disabled in config
+    pub async fn send_unique(
+        &self,
+        id: Option<&str>,
+        update: UnifyedContext,
+    ) -> Result<(), UnifyedContext> {
+        // VK event IDs and Telegram update IDs may coincide
+        let key = id.map(|id| match update.platform {
+            Platform::VK => format!("vk:{}", id),
+            Platform::Telegram => format!("tg:{}", id),
+        });
+        if let (Some(dedup), Some(id)) = (&self.dedup, &key)
+            && dedup.lock().unwrap().is_duplicate(id, Instant::now())
+        {
+            debug!("Dropping repeated update {}", id);
+            return Ok(());
+        }
+        self.send(update).await
+    }
+}
+
+// Updates of one chat always go to the same queue, so they are processed in order
+pub(crate) fn queue_index(update: &UnifyedContext, queues: usize) -> usize {
+    if queues == 1 {
+        return 0;
+    }
+    let chat = if update.peer_id != 0 {
+        update.peer_id
+    } else {
+        update.from_id
+    };
+    (chat.unsigned_abs() % queues as u64) as usize
+}
+
+/// Pool of worker tasks which execute middleware chain for incoming updates
+///
+/// Updates are passed to the workers through the [`sender`](Dispatcher::sender)
+#[derive(Debug)]
+pub struct Dispatcher {
+    queues: Arc<Vec<UpdateQueue>>,
+    dedup: Option<Arc<Mutex<DedupCache>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Dispatcher {
+    /// Starts worker tasks for the middleware chain
+    ///
+    /// In [`DispatchMode::Shared`] all workers take updates from one queue,
+    /// in [`DispatchMode::PerChat`] every worker has its own queue and updates are assigned by chat
+    pub fn start(middleware: MiddlewareChain, config: &Config) -> Self {
+        let settings = &config.dispatch;
+        let workers_count = settings.workers.max(1);
+        let queues_count = match settings.mode {
+            DispatchMode::Shared => 1,
+            DispatchMode::PerChat => workers_count,
+        };
+        let queues: Arc<Vec<UpdateQueue>> = Arc::new(
+            (0..queues_count)
+                .map(|_| UpdateQueue::new(settings.queue_capacity, settings.overflow.clone()))
+                .collect(),
+        );
+        let middleware = Arc::new(middleware);
+        let workers = (0..workers_count)
+            .map(|i| {
+                let queues_clone = Arc::clone(&queues);
+                let middleware_clone = Arc::clone(&middleware);
+                tokio::task::spawn(async move {
+                    let queue = &queues_clone[i % queues_clone.len()];
+                    while let Some(update) = queue.pop().await {
+                        if log_enabled!(log::Level::Debug) {
+                            let start_time = Instant::now();
+                            middleware_clone.execute(update).await;
+                            let end_time = Instant::now();
+                            let elapsed_time = end_time.duration_since(start_time);
+                            debug!("Processing time: {:?}", elapsed_time);
+                        } else {
+                            middleware_clone.execute(update).await;
+                        }
+                    }
+                })
+            })
+            .collect();
+        let dedup = config
+            .dedup
+            .as_ref()
+            .map(|dedup| Arc::new(Mutex::new(DedupCache::new(dedup))));
+        Dispatcher {
+            queues,
+            dedup,
+            workers,
+        }
+    }
+
+    /// Sender to pass updates to the workers
+    pub fn sender(&self) -> UpdateSender {
+        UpdateSender {
+            queues: Arc::clone(&self.queues),
+            dedup: self.dedup.clone(),
+        }
+    }
+
+    /// Stops accepting updates, waits for the workers to process queued ones, then stops them
+    ///
+    /// Workers which don't finish within `timeout` are aborted
+    pub async fn shutdown(self, timeout: Duration) {
+        for queue in self.queues.iter() {
+            queue.close();
+        }
+        let deadline = Instant::now() + timeout;
+        for worker in self.workers {
+            let abort = worker.abort_handle();
+            if timeout_at(deadline, worker).await.is_err() {
+                warn!("Worker didn't finish processing updates in time, aborting");
+                abort.abort();
+            }
+        }
+    }
+}
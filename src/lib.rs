@@ -75,9 +75,13 @@
 //!        callback: Some(CallbackSettings {
 //!            port: 1234,
 //!            callback_url: "https://valnesfjord.com".to_string(),
-//!            secret: "secret".to_string(),
 //!            path: "yourcallbacksecretpathwithoutslashinstartandend".to_string(),
+//!            vk_confirmation: "a1b2c3d4".to_string(),
+//!            vk_secret: "vksecret".to_string(),
+//!            tg_secret_token: "tgsecret".to_string(),
+//!            ..Default::default()
 //!        }),
+//!        ..Default::default()
 //!    };
 //!
 //!    let mut middleware_chain = MiddlewareChain::new();
@@ -107,6 +111,11 @@
 /// This module contains function for getting updates from VK and Telegram
 pub mod client;
 
+/// Module for dispatching updates to the middleware chain
+///
+/// This module contains worker pool used by longpoll client and callback server
+pub mod dispatcher;
+
 /// Module for keyboard creation
 ///
 /// This module contains function for creating keyboards for VK and Telegram
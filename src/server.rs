@@ -1,96 +1,252 @@
-use crate::client::api_requests::api_call;
-use crate::structs::config::Config;
-use crate::structs::context::{Platform, UnifyContext, UnifyedContext};
+use crate::client::api_requests::{ApiError, api_call};
+use crate::dispatcher::{Dispatcher, UpdateSender};
+use crate::structs::config::{CallbackBind, CallbackSettings, Config, VKCallbackRegistration};
+use crate::structs::context::{EventType, Platform, UnifyContext, UnifyedContext};
 use crate::structs::middleware::MiddlewareChain;
 use crate::structs::struct_to_vec::param;
 use crate::structs::tg::TGUpdate;
 use crate::structs::vk::VKUpdate;
 use bytes::Bytes;
-use http_body_util::{BodyExt, Full};
-use hyper::body::Incoming;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::Body;
 use hyper::service::Service;
 use hyper::{Request, Response};
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyper_util::server;
-use log::{debug, error, info, log_enabled};
-use std::future::Future;
-use std::panic;
+use hyper_util::server::graceful::GracefulShutdown;
+use log::{debug, error, info, warn};
+use serde::de::DeserializeOwned;
+use std::convert::Infallible;
+use std::error::Error;
+use std::future::{Future, pending};
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::pin::Pin;
-use std::sync::Arc;
-use std::{convert::Infallible, net::SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::select;
 use tokio::signal;
-use tokio::sync::Mutex;
-use tokio::sync::mpsc::{Receiver, Sender, channel};
-use tokio::time::Instant;
-struct Cleanup {
-    config: Arc<Config>,
+use tokio::time::{Instant, timeout_at};
+
+/// Webhook request body which was rejected as malformed
+///
+/// Passed to `CallbackSettings.on_rejected` hook
+/// # Fields
+/// * `platform` - Platform the request was sent to
+/// * `body` - Raw request body
+/// * `reason` - Why the body was rejected
+#[derive(Debug, Clone)]
+pub struct RejectedPayload {
+    pub platform: Platform,
+    pub body: Bytes,
+    pub reason: String,
 }
 
-impl Drop for Cleanup {
-    fn drop(&mut self) {
-        let config = Arc::clone(&self.config);
-        tokio::task::spawn(async move {
-            api_call(Platform::Telegram, "deleteWebhook", vec![], &config)
-                .await
-                .unwrap();
-            info!("Shutting down...");
+const LOGGED_PAYLOAD_LIMIT: usize = 1024;
+
+// Updates are far smaller, the limit keeps unauthenticated requests from being buffered without bound
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+fn reject_payload(platform: Platform, body: Bytes, reason: String, settings: &CallbackSettings) {
+    let logged = &body[..body.len().min(LOGGED_PAYLOAD_LIMIT)];
+    warn!(
+        "[CALLBACK] [{:?}] Rejected malformed update: {} ({})",
+        platform,
+        reason,
+        String::from_utf8_lossy(logged)
+    );
+    if let Some(on_rejected) = &settings.on_rejected {
+        on_rejected(&RejectedPayload {
+            platform,
+            body,
+            reason,
         });
     }
 }
-async fn handle_request(
-    req: Request<Incoming>,
-    config: Arc<Config>,
-    tx: Arc<Sender<UnifyedContext>>,
-) -> Result<Response<Full<Bytes>>, Infallible> {
-    let uri = req.uri();
+
+async fn read_body<B>(req: Request<B>, log_prefix: &str) -> Result<Bytes, Response<Full<Bytes>>>
+where
+    B: Body,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    match Limited::new(req.into_body(), MAX_BODY_SIZE).collect().await {
+        Ok(body) => Ok(body.to_bytes()),
+        Err(err) if err.is::<LengthLimitError>() => {
+            warn!(
+                "{} Rejected request body larger than {} bytes",
+                log_prefix, MAX_BODY_SIZE
+            );
+            Err(payload_too_large_response())
+        }
+        Err(err) => {
+            error!("{} Failed to read request body: {}", log_prefix, err);
+            Err(bad_request_response())
+        }
+    }
+}
+
+fn parse_update<T: DeserializeOwned>(
+    platform: Platform,
+    body: Bytes,
+    settings: &CallbackSettings,
+) -> Option<T> {
+    match serde_json::from_slice(&body) {
+        Ok(update) => Some(update),
+        Err(err) => {
+            reject_payload(platform, body, err.to_string(), settings);
+            None
+        }
+    }
+}
+
+async fn handle_vk<B>(req: Request<B>, route: &Route) -> Response<Full<Bytes>>
+where
+    B: Body,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    let config = &route.config;
     let settings = config.callback.as_ref().unwrap();
-    match uri.path() {
-        path if path == format!("/{}/vk", settings.path) => {
-            let bytes = req.collect().await.unwrap().to_bytes();
-            let update: VKUpdate =
-                serde_json::from_str(String::from_utf8(bytes.to_vec()).unwrap().as_str()).unwrap();
-            if update.r#type == "confirmation" {
-                return Ok(Response::builder()
-                    .status(200)
-                    .header("Content-Type", "text/plain")
-                    .body(Full::from(config.callback.clone().unwrap().secret.clone()))
-                    .unwrap());
-            }
-            debug!("[CALLBACK] [VK] Got update, processing");
-            tx.send(update.unify(config.clone())).await.unwrap();
-            Response::builder().status(200)
-        }
-        path if path == format!("/{}/telegram", settings.path) => {
-            let headers = req.headers();
-            let secret_token = match headers.get("X-Telegram-Bot-Api-Secret-Token") {
-                Some(value) => value.to_str().unwrap(),
-                None => "",
-            };
-            if *secret_token != settings.secret {
-                return Ok(Response::builder()
-                    .status(403)
-                    .header("Content-Type", "text/plain")
-                    .body(Full::from("Forbidden"))
-                    .unwrap());
-            }
-            let bytes = req.collect().await.unwrap().to_bytes();
-            let update: TGUpdate = serde_json::from_str(core::str::from_utf8(&bytes).unwrap())
-                .unwrap_or(TGUpdate {
-                    ..Default::default()
-                });
-            debug!("[WEBHOOK] [TELEGRAM] Got update, processing");
-            tx.send(update.unify(config.clone())).await.unwrap();
-            Response::builder().status(200)
-        }
-        _ => Response::builder().status(404),
+    if !config.vk_enabled() {
+        return not_found_response();
+    }
+    let bytes = match read_body(req, "[CALLBACK] [VK]").await {
+        Ok(bytes) => bytes,
+        Err(response) => return response,
+    };
+    let Some(update) = parse_update::<VKUpdate>(Platform::VK, bytes, settings) else {
+        return bad_request_response();
     };
+    if update
+        .group_id
+        .is_some_and(|group_id| group_id != config.vk_group_id)
+    {
+        return forbidden_response();
+    }
+    if update.r#type == "confirmation" {
+        return Response::builder()
+            .status(200)
+            .header("Content-Type", "text/plain")
+            .body(Full::from(settings.vk_confirmation.clone()))
+            .unwrap();
+    }
+    if !secrets_eq(update.secret.as_deref().unwrap_or(""), &settings.vk_secret) {
+        warn!("[CALLBACK] [VK] Rejected update with invalid secret");
+        return forbidden_response();
+    }
+    debug!("[CALLBACK] [VK] Got update, processing");
+    let event_id = update.event_id.clone();
+    dispatch(event_id.as_deref(), update.unify(config.clone()), &route.tx).await
+}
+
+async fn handle_telegram<B>(req: Request<B>, route: &Route) -> Response<Full<Bytes>>
+where
+    B: Body,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    let config = &route.config;
+    let settings = config.callback.as_ref().unwrap();
+    if !config.tg_enabled() {
+        return not_found_response();
+    }
+    let headers = req.headers();
+    let secret_token = match headers.get("X-Telegram-Bot-Api-Secret-Token") {
+        Some(value) => value.to_str().unwrap_or(""),
+        None => "",
+    };
+    if !secrets_eq(secret_token, &settings.tg_secret_token) {
+        warn!("[WEBHOOK] [TELEGRAM] Rejected update with invalid secret token");
+        return forbidden_response();
+    }
+    let bytes = match read_body(req, "[WEBHOOK] [TELEGRAM]").await {
+        Ok(bytes) => bytes,
+        Err(response) => return response,
+    };
+    let Some(update) = parse_update::<TGUpdate>(Platform::Telegram, bytes, settings) else {
+        return bad_request_response();
+    };
+    debug!("[WEBHOOK] [TELEGRAM] Got update, processing");
+    let update_id = update.update_id.to_string();
+    dispatch(Some(&update_id), update.unify(config.clone()), &route.tx).await
+}
 
-    Ok(Response::builder()
+async fn dispatch(
+    id: Option<&str>,
+    update: UnifyedContext,
+    tx: &UpdateSender,
+) -> Response<Full<Bytes>> {
+    // Update types which aren't supported are acknowledged, so they are not redelivered
+    if update.r#type == EventType::Unknown {
+        debug!(
+            "[CALLBACK] [{:?}] Skipping update of unsupported type",
+            update.platform
+        );
+    } else if tx.send_unique(id, update).await.is_err() {
+        return shutting_down_response();
+    }
+    Response::builder()
         .status(200)
         .body(Full::from("OK"))
-        .unwrap())
+        .unwrap()
+}
+
+fn bad_request_response() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(400)
+        .header("Content-Type", "text/plain")
+        .body(Full::from("Bad Request"))
+        .unwrap()
+}
+
+// Compares secrets in constant time to not leak them through response timing
+fn secrets_eq(received: &str, expected: &str) -> bool {
+    received.len() == expected.len()
+        && received
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn payload_too_large_response() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(413)
+        .header("Content-Type", "text/plain")
+        .body(Full::from("Payload Too Large"))
+        .unwrap()
+}
+
+fn forbidden_response() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(403)
+        .header("Content-Type", "text/plain")
+        .body(Full::from("Forbidden"))
+        .unwrap()
+}
+
+fn not_found_response() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(404)
+        .header("Content-Type", "text/plain")
+        .body(Full::from("Not Found"))
+        .unwrap()
+}
+
+fn shutting_down_response() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(503)
+        .header("Content-Type", "text/plain")
+        .body(Full::from("Shutting down"))
+        .unwrap()
 }
+
 ///Starts callback server for getting updates from VK and Telegram
 ///
 ///Accepts middleware chain and config
@@ -127,9 +283,13 @@ async fn handle_request(
 ///        callback: Some(CallbackSettings {
 ///            port: 1234,
 ///            callback_url: "https://valnesfjord.com".to_string(),
-///            secret: "secret".to_string(),
 ///            path: "yourcallbacksecretpathwithoutslashinstartandend".to_string(),
+///            vk_confirmation: "a1b2c3d4".to_string(),
+///            vk_secret: "vksecret".to_string(),
+///            tg_secret_token: "tgsecret".to_string(),
+///            ..Default::default()
 ///        }),
+///        ..Default::default()
 ///    };
 ///
 ///    let mut middleware_chain = MiddlewareChain::new();
@@ -139,110 +299,597 @@ async fn handle_request(
 ///}
 ///```
 pub async fn start_callback_server(middleware: MiddlewareChain, config: Config) {
-    let config = config.check();
-    if config.callback.is_none() {
-        panic!("Callback settings don't exist in config");
+    start_callback_server_with_shutdown(middleware, config, async {
+        if let Err(err) = signal::ctrl_c().await {
+            error!("Unable to listen for shutdown signal: {}", err);
+            pending::<()>().await;
+        }
+    })
+    .await;
+}
+
+///Starts callback server and runs it until `shutdown` future completes
+///
+///On shutdown server stops accepting connections, waits for open connections and updates in processing
+///(up to `config.shutdown_timeout`), deletes Telegram webhook and returns
+///
+///If VK confirmation code can't be received at start, the error is logged and the function returns
+pub async fn start_callback_server_with_shutdown<F>(
+    middleware: MiddlewareChain,
+    config: Config,
+    shutdown: F,
+) where
+    F: Future<Output = ()>,
+{
+    start_callback_servers_with_shutdown(vec![(middleware, config)], shutdown).await;
+}
+
+///Starts one callback server for several bot accounts
+///
+///Accepts pairs of middleware chain and config, every account gets own dispatcher.
+///All accounts must use the same callback address and different callback paths, updates are routed by path.
+///
+///# Examples
+///
+///```no_run
+///use vtg::{
+///    server::start_callback_servers,
+///    structs::{
+///        config::{CallbackSettings, Config},
+///        middleware::MiddlewareChain,
+///    },
+///};
+///
+///#[tokio::main]
+///async fn main() {
+///    let callback = |path: &str| CallbackSettings {
+///        port: 1234,
+///        callback_url: "https://valnesfjord.com".to_string(),
+///        path: path.to_string(),
+///        vk_confirmation: "a1b2c3d4".to_string(),
+///        vk_secret: "vksecret".to_string(),
+///        tg_secret_token: "tgsecret".to_string(),
+///        ..Default::default()
+///    };
+///    let first = Config {
+///        name: "first".to_string(),
+///        tg_access_token: "TG_ACCESS_TOKEN_1".to_string(),
+///        callback: Some(callback("first")),
+///        ..Default::default()
+///    };
+///    let second = Config {
+///        name: "second".to_string(),
+///        tg_access_token: "TG_ACCESS_TOKEN_2".to_string(),
+///        callback: Some(callback("second")),
+///        ..Default::default()
+///    };
+///
+///    let middleware_chain = MiddlewareChain::new();
+///    start_callback_servers(vec![
+///        (middleware_chain.clone(), first),
+///        (middleware_chain, second),
+///    ])
+///    .await;
+///}
+///```
+pub async fn start_callback_servers(bots: Vec<(MiddlewareChain, Config)>) {
+    start_callback_servers_with_shutdown(bots, async {
+        if let Err(err) = signal::ctrl_c().await {
+            error!("Unable to listen for shutdown signal: {}", err);
+            pending::<()>().await;
+        }
+    })
+    .await;
+}
+
+///Starts one callback server for several bot accounts and runs it until `shutdown` future completes
+///
+///If VK confirmation code of any account can't be received at start, the error is logged and the function returns
+pub async fn start_callback_servers_with_shutdown<F>(
+    bots: Vec<(MiddlewareChain, Config)>,
+    shutdown: F,
+) where
+    F: Future<Output = ()>,
+{
+    let mut bind = None;
+    for (_, config) in &bots {
+        let Some(settings) = &config.callback else {
+            panic!("Callback settings don't exist in config");
+        };
+        if *bind.get_or_insert(settings.bind_address()) != settings.bind_address() {
+            panic!("All accounts must use the same callback address");
+        }
     }
-    let settings = config.callback.as_ref().unwrap();
-    let (tx, rx): (Sender<UnifyedContext>, Receiver<UnifyedContext>) = channel(100);
-    let rx = Arc::new(Mutex::new(rx));
-    let middleware = Arc::new(middleware);
-    for _i in 0..4 {
-        let rx_clone = Arc::clone(&rx);
-        let middleware_clone = Arc::clone(&middleware);
-        tokio::task::spawn(async move {
-            loop {
-                if let Some(update) = rx_clone.lock().await.recv().await {
-                    if log_enabled!(log::Level::Debug) {
-                        let start_time = Instant::now();
-                        middleware_clone.execute(update).await;
-                        let end_time = Instant::now();
-                        let elapsed_time = end_time.duration_since(start_time);
-                        debug!("Processing time: {:?}", elapsed_time);
-                    } else {
-                        middleware_clone.execute(update).await;
-                    }
+    let Some(bind) = bind else {
+        panic!("No accounts to start callback server for");
+    };
+
+    let service = match CallbackService::start(bots).await {
+        Ok(service) => service,
+        Err(err) => {
+            error!(
+                "[CALLBACK] [VK] Unable to get callback confirmation code: {}",
+                err
+            );
+            return;
+        }
+    };
+    let listener = Listener::bind(&bind).await.unwrap();
+    info!("Callback server listening on {:?}", bind);
+    let builder = server::conn::auto::Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    // VK checks the server right after registration, so it runs while connections are accepted
+    let registration = service.register_vk();
+    tokio::pin!(registration);
+    let mut registered = false;
+
+    loop {
+        select! {
+            _ = listener.accept(&builder, &graceful, &service) => {},
+            _ = &mut registration, if !registered => registered = true,
+            _ = &mut shutdown => break,
+        }
+    }
+
+    info!("Shutting down...");
+    listener.close();
+    let deadline = Instant::now() + service.shutdown_timeout();
+    if timeout_at(deadline, graceful.shutdown()).await.is_err() {
+        warn!("Connections didn't close in time");
+    }
+    service
+        .shutdown(deadline.saturating_duration_since(Instant::now()))
+        .await;
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    async fn bind(bind: &CallbackBind) -> io::Result<Self> {
+        match bind {
+            CallbackBind::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            CallbackBind::Unix(path) => {
+                // Socket file left after previous run would make bind fail
+                if std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
                 }
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.clone()))
             }
-        });
+        }
+    }
+
+    async fn accept(
+        &self,
+        builder: &server::conn::auto::Builder<TokioExecutor>,
+        graceful: &GracefulShutdown,
+        service: &CallbackService,
+    ) {
+        let accepted = match self {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, _)| serve_connection(stream, builder, graceful, service)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener
+                .accept()
+                .await
+                .map(|(stream, _)| serve_connection(stream, builder, graceful, service)),
+        };
+        if let Err(err) = accepted {
+            error!("Error accepting connection: {:?}", err);
+        }
     }
-    api_call(
-        Platform::Telegram,
-        "setWebhook",
-        vec![
-            param(
-                "url",
-                format!("{}/{}/telegram", settings.callback_url, settings.path),
-            ),
-            param("secret_token", settings.secret.clone()),
-        ],
-        &config,
+
+    fn close(self) {
+        #[cfg(unix)]
+        if let Listener::Unix(listener, path) = self {
+            drop(listener);
+            if let Err(err) = std::fs::remove_file(&path) {
+                warn!("Unable to remove socket file {:?}: {}", path, err);
+            }
+        }
+    }
+}
+
+fn serve_connection<IO>(
+    io: IO,
+    builder: &server::conn::auto::Builder<TokioExecutor>,
+    graceful: &GracefulShutdown,
+    service: &CallbackService,
+) where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let io = TokioIo::new(io);
+    let connection = graceful.watch(builder.serve_connection(io, service.clone()).into_owned());
+    tokio::task::spawn(async move {
+        if let Err(err) = connection.await {
+            error!("Error serving connection: {:?}", err);
+        }
+    });
+}
+
+async fn set_webhook(config: &Config) {
+    let settings = config.callback.as_ref().unwrap();
+    if config.tg_enabled() {
+        let url = format!("{}/{}/telegram", settings.callback_url, settings.path);
+        let mut params = vec![
+            param("url", url.clone()),
+            param("secret_token", settings.tg_secret_token.clone()),
+        ];
+        if let Some(updates) = &config.tg_updates {
+            if let Some(allowed_updates) = updates.allowed_updates_param() {
+                params.push(param("allowed_updates", allowed_updates));
+            }
+            if updates.drop_pending_updates {
+                params.push(param("drop_pending_updates", "true"));
+            }
+            if let Some(max_connections) = updates.max_connections {
+                params.push(param("max_connections", max_connections.to_string()));
+            }
+            if let Some(ip_address) = &updates.ip_address {
+                params.push(param("ip_address", ip_address.clone()));
+            }
+        }
+        api_call(Platform::Telegram, "setWebhook", params, config)
+            .await
+            .unwrap();
+        debug!("[WEBHOOK] [TELEGRAM] Webhook set to {}", url);
+    }
+}
+
+async fn get_vk_confirmation_code(config: &Config) -> Result<String, ApiError> {
+    let response = api_call(
+        Platform::VK,
+        "groups.getCallbackConfirmationCode",
+        vec![param("group_id", config.vk_group_id.to_string())],
+        config,
     )
-    .await
-    .unwrap();
-    let addr = SocketAddr::from(([0, 0, 0, 0], settings.port));
+    .await?;
+    Ok(serde_json::from_value(
+        response["response"]["code"].clone(),
+    )?)
+}
+
+async fn register_vk_server(
+    config: &Config,
+    registration: &VKCallbackRegistration,
+) -> Result<(), ApiError> {
+    let settings = config.callback.as_ref().unwrap();
+    let group_id = config.vk_group_id.to_string();
+    let url = format!("{}/{}/vk", settings.callback_url, settings.path);
+
+    let servers = api_call(
+        Platform::VK,
+        "groups.getCallbackServers",
+        vec![param("group_id", group_id.as_str())],
+        config,
+    )
+    .await?;
+    let existing = servers["response"]["items"]
+        .as_array()
+        .and_then(|items| items.iter().find(|server| server["url"] == url.as_str()))
+        .and_then(|server| server["id"].as_i64());
+
+    let mut params = vec![
+        param("group_id", group_id.as_str()),
+        param("url", url.as_str()),
+        param("title", registration.title.as_str()),
+        param("secret_key", settings.vk_secret.as_str()),
+    ];
+    let server_id = match existing {
+        Some(server_id) => {
+            params.push(param("server_id", server_id.to_string()));
+            api_call(Platform::VK, "groups.editCallbackServer", params, config).await?;
+            server_id
+        }
+        None => {
+            let added = api_call(Platform::VK, "groups.addCallbackServer", params, config).await?;
+            serde_json::from_value(added["response"]["server_id"].clone())?
+        }
+    };
+
+    let mut params = vec![
+        param("group_id", group_id.as_str()),
+        param("server_id", server_id.to_string()),
+        param("api_version", config.vk_api_version.as_str()),
+    ];
+    params.extend(
+        registration
+            .events
+            .iter()
+            .map(|event| param(event.as_str(), "1")),
+    );
+    api_call(Platform::VK, "groups.setCallbackSettings", params, config).await?;
     debug!(
-        "Callback server started on http://0.0.0.0:{}/{}/telegram and http://0.0.0.0:{}/{}/vk",
-        settings.port, settings.path, settings.port, settings.path
+        "[CALLBACK] [VK] Callback server {} registered with id {}",
+        url, server_id
     );
+    Ok(())
+}
 
-    let cfg = Arc::new(config);
-    let cleanup = Cleanup {
-        config: Arc::clone(&cfg),
-    };
-    tokio::task::spawn(async move {
-        match signal::ctrl_c().await {
-            Ok(()) => {
-                api_call(Platform::Telegram, "deleteWebhook", vec![], &cleanup.config)
-                    .await
-                    .unwrap();
-                info!("Shutting down...");
-                std::process::exit(0);
+#[derive(Debug, Clone)]
+struct Route {
+    config: Arc<Config>,
+    tx: UpdateSender,
+}
+
+impl Route {
+    fn matches(&self, path: &str, platform: &str) -> bool {
+        let settings = self.config.callback.as_ref().unwrap();
+        path.strip_prefix('/')
+            .and_then(|path| path.strip_prefix(settings.path.as_str()))
+            .and_then(|path| path.strip_prefix('/'))
+            .is_some_and(|path| path == platform)
+    }
+}
+
+#[derive(Debug)]
+struct CallbackInner {
+    routes: Vec<Route>,
+    dispatchers: Mutex<Vec<Dispatcher>>,
+}
+
+/// VK callback and Telegram webhook handler, which can be embedded into own hyper or axum server
+///
+/// Requests to `/{path}/vk` and `/{path}/telegram` (`path` from callback settings of every account) are routed to the accounts.
+/// [`handle_vk_for`](CallbackService::handle_vk_for) and [`handle_telegram_for`](CallbackService::handle_telegram_for)
+/// skip path matching, so the handler of every account can be mounted at any path.
+/// The service implements both hyper and tower `Service`, so it can be served by hyper or mounted with axum `nest_service`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use vtg::{
+///     server::CallbackService,
+///     structs::{
+///         config::{CallbackSettings, Config},
+///         middleware::MiddlewareChain,
+///     },
+/// };
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let config = Config {
+///         tg_access_token: "TG_ACCESS_TOKEN".to_string(),
+///         callback: Some(CallbackSettings {
+///             callback_url: "https://valnesfjord.com".to_string(),
+///             path: "bot".to_string(),
+///             tg_secret_token: "tgsecret".to_string(),
+///             ..Default::default()
+///         }),
+///         ..Default::default()
+///     };
+///     let service = CallbackService::start(vec![(MiddlewareChain::new(), config)])
+///         .await
+///         .unwrap();
+///
+///     // serve `service` with hyper, mount it with axum `nest_service`,
+///     // or call `service.handle_telegram_for("bot", request)` from your router
+///
+///     service.shutdown(std::time::Duration::from_secs(10)).await;
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CallbackService {
+    inner: Arc<CallbackInner>,
+}
+
+impl CallbackService {
+    /// Starts dispatchers, sets Telegram webhooks and gets VK confirmation codes (with `vk_register`) for the accounts
+    ///
+    /// Every config must have callback settings, callback paths must be different.
+    /// Fails if a VK confirmation code can't be received, nothing is started in that case
+    pub async fn start(bots: Vec<(MiddlewareChain, Config)>) -> Result<Self, ApiError> {
+        let mut accounts: Vec<(MiddlewareChain, Config)> = Vec::new();
+        for (middleware, config) in bots {
+            let mut config = config.check();
+            let Some(settings) = &config.callback else {
+                panic!("Callback settings don't exist in config");
+            };
+            if accounts
+                .iter()
+                .any(|(_, account)| account.callback.as_ref().unwrap().path == settings.path)
+            {
+                panic!(
+                    "Callback path {} is used by several accounts",
+                    settings.path
+                );
             }
-            Err(err) => {
-                error!("Unable to listen for shutdown signal: {}", err);
+            if config.vk_enabled() && settings.vk_register.is_some() {
+                let code = get_vk_confirmation_code(&config).await?;
+                config.callback.as_mut().unwrap().vk_confirmation = code;
             }
+            accounts.push((middleware, config));
         }
-    });
+        let mut routes: Vec<Route> = Vec::new();
+        let mut dispatchers = Vec::new();
+        for (middleware, config) in accounts {
+            let dispatcher = Dispatcher::start(middleware, &config);
+            set_webhook(&config).await;
+            routes.push(Route {
+                config: Arc::new(config),
+                tx: dispatcher.sender(),
+            });
+            dispatchers.push(dispatcher);
+        }
+        Ok(CallbackService {
+            inner: Arc::new(CallbackInner {
+                routes,
+                dispatchers: Mutex::new(dispatchers),
+            }),
+        })
+    }
 
-    let tx = Arc::new(tx);
-    let service = Svc {
-        config: Arc::clone(&cfg),
-        tx: Arc::clone(&tx),
-    };
+    /// Registers VK callback servers of the accounts with `vk_register` settings
+    ///
+    /// VK sends confirmation request to the server right away, so it must be called when requests are already served.
+    /// Callback server started by [`start_callback_server`] does it itself
+    pub async fn register_vk(&self) {
+        for route in &self.inner.routes {
+            let config = &route.config;
+            let Some(registration) = &config.callback.as_ref().unwrap().vk_register else {
+                continue;
+            };
+            if !config.vk_enabled() {
+                continue;
+            }
+            if let Err(err) = register_vk_server(config, registration).await {
+                error!(
+                    "[CALLBACK] [VK] Unable to register callback server: {}",
+                    err
+                );
+            }
+        }
+    }
 
-    let listener = TcpListener::bind(addr).await.unwrap();
+    /// Handles request, routing it to the account and platform by path
+    pub async fn handle<B>(&self, req: Request<B>) -> Response<Full<Bytes>>
+    where
+        B: Body,
+        B::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        let path = req.uri().path();
+        for route in &self.inner.routes {
+            if route.matches(path, "vk") {
+                return handle_vk(req, route).await;
+            }
+            if route.matches(path, "telegram") {
+                return handle_telegram(req, route).await;
+            }
+        }
+        not_found_response()
+    }
 
-    loop {
-        let (stream, _) = listener.accept().await.unwrap();
-        let io = TokioIo::new(stream);
-        let svc_clone = service.clone();
-
-        tokio::task::spawn(async move {
-            if let Err(err) = server::conn::auto::Builder::new(TokioExecutor::new())
-                .http1()
-                .serve_connection(io, svc_clone)
-                .await
+    /// Handles VK callback request for the first account, regardless of path
+    pub async fn handle_vk<B>(&self, req: Request<B>) -> Response<Full<Bytes>>
+    where
+        B: Body,
+        B::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        match self.inner.routes.first() {
+            Some(route) => handle_vk(req, route).await,
+            None => not_found_response(),
+        }
+    }
+
+    /// Handles Telegram webhook request for the first account, regardless of path
+    pub async fn handle_telegram<B>(&self, req: Request<B>) -> Response<Full<Bytes>>
+    where
+        B: Body,
+        B::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        match self.inner.routes.first() {
+            Some(route) => handle_telegram(req, route).await,
+            None => not_found_response(),
+        }
+    }
+
+    /// Handles VK callback request for the account, regardless of path
+    ///
+    /// `account` is matched against config `name` and callback settings `path`, unknown accounts are answered with 404
+    pub async fn handle_vk_for<B>(&self, account: &str, req: Request<B>) -> Response<Full<Bytes>>
+    where
+        B: Body,
+        B::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        match self.route(account) {
+            Some(route) => handle_vk(req, route).await,
+            None => not_found_response(),
+        }
+    }
+
+    /// Handles Telegram webhook request for the account, regardless of path
+    ///
+    /// `account` is matched against config `name` and callback settings `path`, unknown accounts are answered with 404
+    pub async fn handle_telegram_for<B>(
+        &self,
+        account: &str,
+        req: Request<B>,
+    ) -> Response<Full<Bytes>>
+    where
+        B: Body,
+        B::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        match self.route(account) {
+            Some(route) => handle_telegram(req, route).await,
+            None => not_found_response(),
+        }
+    }
+
+    fn route(&self, account: &str) -> Option<&Route> {
+        self.inner.routes.iter().find(|route| {
+            (!route.config.name.is_empty() && route.config.name == account)
+                || route.config.callback.as_ref().unwrap().path == account
+        })
+    }
+
+    fn shutdown_timeout(&self) -> Duration {
+        self.inner
+            .routes
+            .iter()
+            .map(|route| route.config.shutdown_timeout())
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Deletes Telegram webhooks and waits for updates in processing up to `timeout`
+    ///
+    /// Requests handled after shutdown are answered with 503
+    pub async fn shutdown(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        for route in &self.inner.routes {
+            let config = &route.config;
+            if config.tg_enabled()
+                && let Err(err) =
+                    api_call(Platform::Telegram, "deleteWebhook", vec![], config).await
             {
-                error!("Error serving connection: {:?}", err);
+                error!("Unable to delete Telegram webhook: {}", err);
             }
-        });
+        }
+        let dispatchers = std::mem::take(&mut *self.inner.dispatchers.lock().unwrap());
+        for dispatcher in dispatchers {
+            dispatcher
+                .shutdown(deadline.saturating_duration_since(Instant::now()))
+                .await;
+        }
     }
 }
 
-#[derive(Debug, Clone)]
-struct Svc {
-    config: Arc<Config>,
-    tx: Arc<Sender<UnifyedContext>>,
+impl<B> Service<Request<B>> for CallbackService
+where
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move { Ok(service.handle(req).await) })
+    }
 }
 
-impl Service<Request<Incoming>> for Svc {
+// Tower service for axum `route_service` and `nest_service`
+impl<B> tower_service::Service<Request<B>> for CallbackService
+where
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
     type Response = Response<Full<Bytes>>;
-    type Error = hyper::Error;
+    type Error = Infallible;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
-    fn call(&self, req: Request<Incoming>) -> Self::Future {
-        let config = Arc::clone(&self.config);
-        let tx = Arc::clone(&self.tx);
-        Box::pin(async move { Ok(handle_request(req, config, tx).await.unwrap()) })
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        Service::call(self, req)
     }
 }
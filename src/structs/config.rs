@@ -1,7 +1,30 @@
-/// CallbackSettings struct with the port, callback_url, secret and path.
+use std::fmt;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::context::Platform;
+use crate::client::offset_store::OffsetStore;
+use crate::client::proxy::Proxy;
+use crate::client::transport::{Transport, shared_transport};
+use crate::server::RejectedPayload;
+
+/// CallbackSettings struct with the port, callback_url, path and platform secrets.
 ///
 ///Note: callback_url don't need to have slash in the end, path must be without slash in start and end
 ///
+/// # Fields
+/// * `vk_confirmation` - String VK expects in response to the `confirmation` event (shown in group callback settings), required for VK
+/// * `vk_secret` - Secret key from VK callback settings, every VK callback is checked against it, required for VK
+/// * `tg_secret_token` - Secret token Telegram sends in `X-Telegram-Bot-Api-Secret-Token` header, required for Telegram
+///   (1-256 characters `A-Z`, `a-z`, `0-9`, `_` and `-`)
+/// * `on_rejected` - Called with every malformed request body, which is answered with 400 and not dispatched, see [`RejectedPayloadHook`]
+/// * `bind` - Address to listen on, `0.0.0.0:port` by default, see [`CallbackBind`]
+/// * `vk_register` - Register callback server in VK group settings on start, see [`VKCallbackRegistration`].
+///   `vk_confirmation` may be left empty then, the code is requested from VK
+///
 /// # Examples
 ///
 /// ```
@@ -9,22 +32,555 @@
 ///let callback_settings = CallbackSettings {
 ///  port: 1234,
 ///  callback_url: "https://valnesfjord.com".to_string(),
-///  secret: "secret".to_string(),
 ///  path: "yourcallbacksecretpathwithoutslashinstartandend".to_string(),
+///  vk_confirmation: "a1b2c3d4".to_string(),
+///  vk_secret: "vksecret".to_string(),
+///  tg_secret_token: "tgsecret".to_string(),
+///  ..Default::default()
 ///};
 ///```
-#[derive(Debug, Clone, Default)]
+#[derive(Clone, Default)]
 pub struct CallbackSettings {
     pub port: u16,
     pub callback_url: String,
-    pub secret: String,
     pub path: String,
+    pub vk_confirmation: String,
+    pub vk_secret: String,
+    pub tg_secret_token: String,
+    pub on_rejected: Option<RejectedPayloadHook>,
+    pub bind: Option<CallbackBind>,
+    pub vk_register: Option<VKCallbackRegistration>,
+}
+
+impl fmt::Debug for CallbackSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallbackSettings")
+            .field("port", &self.port)
+            .field("callback_url", &self.callback_url)
+            .field("path", &self.path)
+            .field("vk_confirmation", &self.vk_confirmation)
+            .field("vk_secret", &redact(&self.vk_secret))
+            .field("tg_secret_token", &redact(&self.tg_secret_token))
+            .field("on_rejected", &self.on_rejected.is_some())
+            .field("bind", &self.bind)
+            .field("vk_register", &self.vk_register)
+            .finish()
+    }
+}
+
+// Secrets are hidden in debug output, empty ones are shown to tell unset settings apart
+fn redact(secret: &str) -> &'static str {
+    if secret.is_empty() { "" } else { "<redacted>" }
+}
+
+/// Callback with webhook request body which was rejected as malformed
+pub type RejectedPayloadHook = Arc<dyn Fn(&RejectedPayload) + Send + Sync>;
+
+/// VK callback server registration settings
+///
+/// On start the server `{callback_url}/{path}/vk` is added to group callback servers (or updated, if it already exists)
+/// with `vk_secret` as secret key, and the events are enabled for it.
+/// # Fields
+/// * `title` - Server title in group settings, up to 14 characters
+/// * `events` - Event types to enable, `message_new` and `message_event` by default
+#[derive(Debug, Clone)]
+pub struct VKCallbackRegistration {
+    pub title: String,
+    pub events: Vec<String>,
+}
+
+impl Default for VKCallbackRegistration {
+    fn default() -> Self {
+        VKCallbackRegistration {
+            title: "vtg".to_string(),
+            events: vec!["message_new".to_string(), "message_event".to_string()],
+        }
+    }
+}
+
+impl CallbackSettings {
+    /// Address callback server listens on, `bind` or `0.0.0.0:port`
+    pub fn bind_address(&self) -> CallbackBind {
+        self.bind
+            .clone()
+            .unwrap_or_else(|| CallbackBind::Tcp(SocketAddr::from(([0, 0, 0, 0], self.port))))
+    }
+}
+
+/// Address for the callback server to listen on
+///
+/// # Variants
+/// * `Tcp` - TCP address, like `127.0.0.1:8080`
+/// * `Unix` - Unix domain socket path, for servers behind a reverse proxy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallbackBind {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// RateLimitSettings struct with outgoing request limits for VK and Telegram.
+///
+/// Requests over the limit are queued, not failed. Default values follow the documented API limits, zero disables the limit.
+/// # Fields
+/// * `vk_requests_per_second` - Requests per second for one VK community token
+/// * `tg_messages_per_second` - Messages per second for one Telegram bot across all chats
+/// * `tg_chat_messages_per_second` - Messages per second to one Telegram chat
+/// * `tg_group_messages_per_minute` - Messages per minute to one Telegram group or channel
+///
+/// # Examples
+///
+/// ```
+/// use vtg::structs::config::{Config, RateLimitSettings};
+///
+/// let config = Config {
+///    vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+///    vk_group_id: 123456789,
+///    tg_access_token: "TG_ACCESS_TOKEN".to_string(),
+///    rate_limit: Some(RateLimitSettings::default()),
+///    ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateLimitSettings {
+    pub vk_requests_per_second: u32,
+    pub tg_messages_per_second: u32,
+    pub tg_chat_messages_per_second: u32,
+    pub tg_group_messages_per_minute: u32,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        RateLimitSettings {
+            vk_requests_per_second: 20,
+            tg_messages_per_second: 30,
+            tg_chat_messages_per_second: 1,
+            tg_group_messages_per_minute: 20,
+        }
+    }
+}
+
+/// RetrySettings struct with the retry policy for failed API calls.
+///
+/// Delay grows exponentially from `base_delay` up to `max_delay`, Telegram `retry_after` is used as is when present.
+/// If `retry_after` is longer than `max_delay`, the call fails with the flood control error instead of waiting.
+/// # Fields
+/// * `max_attempts` - Maximum number of attempts, including the first one
+/// * `base_delay` - Delay before the second attempt
+/// * `max_delay` - Upper bound for the delay
+/// * `jitter` - Randomize delay between half and full value
+/// * `vk_error_codes` - VK error codes to retry (6 - too many requests, 9 - flood control, 10 - internal server error)
+/// * `tg_error_codes` - Telegram error codes to retry (429 - too many requests)
+/// * `retry_transport_errors` - Retry connection errors, the request isn't sent then, so it's safe to repeat
+/// * `retry_sent_requests` - Also retry timeouts, errors after the request was sent and 5xx responses without API error in body.
+///   Such request may have been processed, so `messages.send` or `sendMessage` can be delivered twice, disabled by default
+///
+/// # Examples
+///
+/// ```
+/// use vtg::structs::config::{Config, RetrySettings};
+///
+/// let config = Config {
+///    tg_access_token: "TG_ACCESS_TOKEN".to_string(),
+///    retry: Some(RetrySettings {
+///        max_attempts: 5,
+///        ..Default::default()
+///    }),
+///    ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct RetrySettings {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: bool,
+    pub vk_error_codes: Vec<i64>,
+    pub tg_error_codes: Vec<i64>,
+    pub retry_transport_errors: bool,
+    pub retry_sent_requests: bool,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        RetrySettings {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: true,
+            vk_error_codes: vec![6, 9, 10],
+            tg_error_codes: vec![429],
+            retry_transport_errors: true,
+            retry_sent_requests: false,
+        }
+    }
+}
+
+/// BatchSettings struct with the settings of VK `execute` batching.
+///
+/// VK API calls made within `window` are sent together in one `execute` request, up to `max_calls` (maximum is 25).
+/// # Fields
+/// * `window` - How long to wait for more calls after the first one
+/// * `max_calls` - Maximum number of calls in one `execute` request
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use vtg::structs::config::{BatchSettings, Config};
+///
+/// let config = Config {
+///    vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+///    vk_group_id: 123456789,
+///    vk_batch: Some(BatchSettings {
+///        window: Duration::from_millis(20),
+///        ..Default::default()
+///    }),
+///    ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct BatchSettings {
+    pub window: Duration,
+    pub max_calls: usize,
+}
+
+impl Default for BatchSettings {
+    fn default() -> Self {
+        BatchSettings {
+            window: Duration::from_millis(50),
+            max_calls: 25,
+        }
+    }
+}
+
+/// Telegram update subscription settings, used both by longpoll (`getUpdates`) and webhook (`setWebhook`)
+/// # Fields
+/// * `allowed_updates` - Update types to receive, like `message` or `chat_member`. `None` keeps the previous setting,
+///   by default Telegram sends all types except `chat_member`, `message_reaction` and `message_reaction_count`
+/// * `drop_pending_updates` - Drop updates which were received while the bot was offline
+/// * `max_connections` - Maximum number of simultaneous webhook connections (1-100), webhook only
+/// * `ip_address` - Fixed IP address to send webhook requests to instead of resolving the domain, webhook only
+///
+/// # Examples
+///
+/// ```
+/// use vtg::structs::config::{Config, TGUpdateSettings};
+///
+/// let config = Config {
+///    tg_access_token: "TG_ACCESS_TOKEN".to_string(),
+///    tg_updates: Some(TGUpdateSettings {
+///        allowed_updates: Some(vec!["message".to_string(), "chat_member".to_string()]),
+///        drop_pending_updates: true,
+///        ..Default::default()
+///    }),
+///    ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TGUpdateSettings {
+    pub allowed_updates: Option<Vec<String>>,
+    pub drop_pending_updates: bool,
+    pub max_connections: Option<u32>,
+    pub ip_address: Option<String>,
+}
+
+impl TGUpdateSettings {
+    /// `allowed_updates` as JSON array for API parameters
+    pub fn allowed_updates_param(&self) -> Option<String> {
+        self.allowed_updates
+            .as_ref()
+            .map(|updates| serde_json::to_string(updates).unwrap())
+    }
+}
+
+/// Update deduplication settings
+///
+/// Updates with VK `event_id` or Telegram `update_id` seen within `ttl` are dropped before dispatching,
+/// so VK callback retries and Telegram redeliveries don't reach the middleware chain twice.
+/// # Fields
+/// * `capacity` - Maximum number of remembered IDs, the oldest are forgotten first
+/// * `ttl` - How long an ID is remembered
+///
+/// # Examples
+///
+/// ```
+/// use vtg::structs::config::{Config, DedupSettings};
+///
+/// let config = Config {
+///    tg_access_token: "TG_ACCESS_TOKEN".to_string(),
+///    dedup: Some(DedupSettings::default()),
+///    ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct DedupSettings {
+    pub capacity: usize,
+    pub ttl: Duration,
+}
+
+impl Default for DedupSettings {
+    fn default() -> Self {
+        DedupSettings {
+            capacity: 10_000,
+            ttl: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Proxy for outgoing requests of one platform
+///
+/// Applies to API calls, longpoll requests, file downloads and uploads.
+/// # Fields
+/// * `url` - Proxy address, `http://host:port` for HTTP CONNECT proxy or `socks5://host:port` for SOCKS5 proxy
+/// * `username` - Username for proxy authentication, empty to connect without authentication
+/// * `password` - Password for proxy authentication
+/// * `no_proxy` - Hosts to connect directly, `example.com` also matches its subdomains, `*` matches every host
+///
+/// # Examples
+///
+/// ```
+/// use vtg::structs::config::{Config, ProxySettings};
+///
+/// let config = Config {
+///    tg_access_token: "TG_ACCESS_TOKEN".to_string(),
+///    tg_proxy: Some(ProxySettings {
+///        url: "socks5://127.0.0.1:1080".to_string(),
+///        username: "user".to_string(),
+///        password: "password".to_string(),
+///        no_proxy: vec!["localhost".to_string()],
+///    }),
+///    ..Default::default()
+/// };
+/// ```
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct ProxySettings {
+    pub url: String,
+    pub username: String,
+    pub password: String,
+    pub no_proxy: Vec<String>,
+}
+
+impl fmt::Debug for ProxySettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxySettings")
+            .field("url", &self.url)
+            .field("username", &self.username)
+            .field("password", &redact(&self.password))
+            .field("no_proxy", &self.no_proxy)
+            .finish()
+    }
+}
+
+/// What to do with a new update when the dispatch queue is full
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum QueueOverflow {
+    /// Wait until workers free up space, slowing down polling
+    #[default]
+    Block,
+    /// Drop the oldest queued update to make room for the new one
+    DropOldest,
+    /// Drop the new update
+    DropNewest,
+}
+
+/// How updates are distributed between dispatch workers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DispatchMode {
+    /// Any free worker takes the next update, updates of one chat may be processed concurrently
+    #[default]
+    Shared,
+    /// Updates are assigned to workers by `peer_id` (or `from_id`), so updates of one chat are processed sequentially
+    PerChat,
+}
+
+/// Update dispatching settings
+///
+/// Updates are put into a queue of `queue_capacity` updates and processed by `workers` concurrent tasks.
+/// # Fields
+/// * `workers` - Number of concurrent worker tasks, 4 by default
+/// * `queue_capacity` - Maximum number of queued updates (per worker in [`DispatchMode::PerChat`]), 100 by default
+/// * `overflow` - What to do when the queue is full, see [`QueueOverflow`]
+/// * `mode` - How updates are distributed between workers, see [`DispatchMode`]
+///
+/// # Examples
+///
+/// ```
+/// use vtg::structs::config::{Config, DispatchMode, DispatchSettings, QueueOverflow};
+///
+/// let config = Config {
+///    tg_access_token: "TG_ACCESS_TOKEN".to_string(),
+///    dispatch: DispatchSettings {
+///        workers: 64,
+///        overflow: QueueOverflow::DropOldest,
+///        mode: DispatchMode::PerChat,
+///        ..Default::default()
+///    },
+///    ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct DispatchSettings {
+    pub workers: usize,
+    pub queue_capacity: usize,
+    pub overflow: QueueOverflow,
+    pub mode: DispatchMode,
+}
+
+impl Default for DispatchSettings {
+    fn default() -> Self {
+        DispatchSettings {
+            workers: 4,
+            queue_capacity: 100,
+            overflow: QueueOverflow::Block,
+            mode: DispatchMode::Shared,
+        }
+    }
+}
+
+/// Timeouts of outgoing requests
+///
+/// A request which doesn't complete in time, including reading the response body, fails with
+/// [`HyperRequestError::Timeout`](crate::client::requests::HyperRequestError::Timeout), a connection which isn't
+/// established in time fails with [`HyperRequestError::ConnectTimeout`](crate::client::requests::HyperRequestError::ConnectTimeout).
+/// # Fields
+/// * `connect` - Time to establish a connection, including proxy handshake, 10 seconds by default
+/// * `request` - Time for an API call, 30 seconds by default
+/// * `longpoll_wait` - How long VK and Telegram hold a long poll request open waiting for updates, 25 seconds by default
+/// * `longpoll` - Time for a long poll request, must be longer than `longpoll_wait`, 40 seconds by default
+/// * `download` - Time to download a file, 60 seconds by default
+/// * `upload` - Time to upload files, 120 seconds by default
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use vtg::structs::config::{Config, TimeoutSettings};
+///
+/// let config = Config {
+///    tg_access_token: "TG_ACCESS_TOKEN".to_string(),
+///    timeouts: TimeoutSettings {
+///        longpoll_wait: Duration::from_secs(50),
+///        longpoll: Duration::from_secs(60),
+///        ..Default::default()
+///    },
+///    ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct TimeoutSettings {
+    pub connect: Duration,
+    pub request: Duration,
+    pub longpoll_wait: Duration,
+    pub longpoll: Duration,
+    pub download: Duration,
+    pub upload: Duration,
+}
+
+impl Default for TimeoutSettings {
+    fn default() -> Self {
+        TimeoutSettings {
+            connect: Duration::from_secs(10),
+            request: Duration::from_secs(30),
+            longpoll_wait: Duration::from_secs(25),
+            longpoll: Duration::from_secs(40),
+            download: Duration::from_secs(60),
+            upload: Duration::from_secs(120),
+        }
+    }
+}
+
+/// Callback with downloaded bytes and total size, if known
+pub type DownloadProgress = Arc<dyn Fn(u64, Option<u64>) + Send + Sync>;
+
+/// File download limits
+///
+/// Used by [`get_file`](crate::client::requests::get_file) and attachment downloads,
+/// and can be passed to [`download`](crate::client::requests::download) directly.
+/// # Fields
+/// * `max_size` - Maximum file size in bytes, 50 MB by default
+/// * `max_redirects` - Maximum number of followed redirects, 10 by default
+/// * `progress` - Called after every received chunk, see [`DownloadProgress`]
+///
+/// # Examples
+///
+/// ```
+/// use vtg::structs::config::{Config, DownloadSettings};
+///
+/// let config = Config {
+///    tg_access_token: "TG_ACCESS_TOKEN".to_string(),
+///    download: DownloadSettings {
+///        max_size: 10 * 1024 * 1024,
+///        ..Default::default()
+///    },
+///    ..Default::default()
+/// };
+/// ```
+#[derive(Clone)]
+pub struct DownloadSettings {
+    pub max_size: u64,
+    pub max_redirects: usize,
+    pub progress: Option<DownloadProgress>,
+}
+
+impl Default for DownloadSettings {
+    fn default() -> Self {
+        DownloadSettings {
+            max_size: 50 * 1024 * 1024,
+            max_redirects: 10,
+            progress: None,
+        }
+    }
+}
+
+impl fmt::Debug for DownloadSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DownloadSettings")
+            .field("max_size", &self.max_size)
+            .field("max_redirects", &self.max_redirects)
+            .field("progress", &self.progress.is_some())
+            .finish()
+    }
 }
 
 /// Config struct with the VK and TG access tokens, VK group ID and VK API version.
 ///
+///A platform with empty access token is disabled: it isn't polled and has no webhook route, so a bot can run on VK or Telegram only.
+///At least one platform must be configured.
+///
 ///Note: If you use callback settings, callback_url don't need to have slash in the end, path must be without slash in start and end
 ///
+///`vk_api_url` and `tg_api_url` override the default API endpoints (`https://api.vk.com/method` and `https://api.telegram.org`),
+///for example to use a self-hosted Telegram Bot API server or a local mock. Plain `http://` URLs are supported.
+///
+///`rate_limit` enables the outgoing request limiter, see [`RateLimitSettings`].
+///
+///`retry` enables automatic retries of failed API calls, see [`RetrySettings`].
+///
+///`vk_batch` enables batching of VK API calls into `execute` requests, see [`BatchSettings`].
+///
+///`name` is an optional account label to tell bots apart when several of them run in one process,
+///handlers get it through `ctx.config.name` and replies are sent with the tokens of the account which received the update.
+///
+///`tg_updates` configures which Telegram updates are received, see [`TGUpdateSettings`].
+///
+///`dispatch` configures worker count and update queue, see [`DispatchSettings`].
+///
+///`offset_store` keeps longpoll positions between restarts, see [`OffsetStore`].
+///
+///`dedup` enables dropping of repeated updates, see [`DedupSettings`].
+///
+///`timeouts` limits connection, API call, long poll, download and upload time, see [`TimeoutSettings`].
+///
+///`download` limits size of downloaded files, see [`DownloadSettings`].
+///
+///`vk_proxy` and `tg_proxy` send requests of the platform through a proxy, see [`ProxySettings`].
+///
+///`transport` replaces the HTTP client used for API calls, file uploads and downloads, see [`Transport`].
+///Proxy settings and connect timeout are not applied to a custom transport.
+///
+///`shutdown_timeout` limits how long updates in processing are awaited on shutdown, 10 seconds by default.
+///
 /// # Examples
 ///
 /// ```
@@ -49,44 +605,206 @@ pub struct CallbackSettings {
 ///    callback: Some(CallbackSettings {
 ///        port: 1234,
 ///        callback_url: "https://valnesfjord.com".to_string(),
-///        secret: "secret".to_string(),
 ///        path: "yourcallbacksecretpathwithoutslashinstartandend".to_string(),
+///        vk_confirmation: "a1b2c3d4".to_string(),
+///        vk_secret: "vksecret".to_string(),
+///        tg_secret_token: "tgsecret".to_string(),
+///        ..Default::default()
 ///    }),
+///    ..Default::default()
 /// };
 ///```
-#[derive(Debug, Clone, Default)]
+/// ```
+/// use vtg::structs::config::Config;
+///
+/// // VK-only bot
+/// let config = Config {
+///    vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+///    vk_group_id: 123456789,
+///    ..Default::default()
+/// };
+/// ```
+/// ```
+/// use vtg::structs::config::Config;
+///
+/// let config = Config {
+///    tg_access_token: "TG_ACCESS_TOKEN".to_string(),
+///    tg_api_url: Some("http://localhost:8081".to_string()),
+///    ..Default::default()
+/// };
+/// ```
+#[derive(Clone, Default)]
 pub struct Config {
+    pub name: String,
     pub vk_access_token: String,
     pub vk_group_id: i64,
     pub vk_api_version: String,
     pub tg_access_token: String,
     pub callback: Option<CallbackSettings>,
+    pub vk_api_url: Option<String>,
+    pub tg_api_url: Option<String>,
+    pub rate_limit: Option<RateLimitSettings>,
+    pub retry: Option<RetrySettings>,
+    pub vk_batch: Option<BatchSettings>,
+    pub shutdown_timeout: Option<Duration>,
+    pub dispatch: DispatchSettings,
+    pub tg_updates: Option<TGUpdateSettings>,
+    pub dedup: Option<DedupSettings>,
+    pub offset_store: Option<Arc<dyn OffsetStore>>,
+    pub timeouts: TimeoutSettings,
+    pub download: DownloadSettings,
+    pub vk_proxy: Option<ProxySettings>,
+    pub tg_proxy: Option<ProxySettings>,
+    pub transport: Option<Arc<dyn Transport>>,
 }
 
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("name", &self.name)
+            .field("vk_access_token", &redact(&self.vk_access_token))
+            .field("vk_group_id", &self.vk_group_id)
+            .field("vk_api_version", &self.vk_api_version)
+            .field("tg_access_token", &redact(&self.tg_access_token))
+            .field("callback", &self.callback)
+            .field("vk_api_url", &self.vk_api_url)
+            .field("tg_api_url", &self.tg_api_url)
+            .field("rate_limit", &self.rate_limit)
+            .field("retry", &self.retry)
+            .field("vk_batch", &self.vk_batch)
+            .field("shutdown_timeout", &self.shutdown_timeout)
+            .field("dispatch", &self.dispatch)
+            .field("tg_updates", &self.tg_updates)
+            .field("dedup", &self.dedup)
+            .field("offset_store", &self.offset_store)
+            .field("timeouts", &self.timeouts)
+            .field("download", &self.download)
+            .field("vk_proxy", &self.vk_proxy)
+            .field("tg_proxy", &self.tg_proxy)
+            .field("transport", &self.transport)
+            .finish()
+    }
+}
+
+/// Default VK API endpoint, methods are appended to it
+pub const VK_API_URL: &str = "https://api.vk.com/method";
+/// Default Telegram Bot API endpoint, token and method are appended to it
+pub const TG_API_URL: &str = "https://api.telegram.org";
+
 impl Config {
+    /// Time to wait for updates in processing on shutdown
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout.unwrap_or(Duration::from_secs(10))
+    }
+    /// Proxy settings of the platform
+    pub fn proxy(&self, platform: &Platform) -> Option<&ProxySettings> {
+        match platform {
+            Platform::VK => self.vk_proxy.as_ref(),
+            Platform::Telegram => self.tg_proxy.as_ref(),
+        }
+    }
+    /// HTTP transport for requests of the platform
+    ///
+    /// Custom `transport` if set, otherwise the default hyper client going through the platform proxy
+    pub fn transport(&self, platform: &Platform) -> Arc<dyn Transport> {
+        if let Some(transport) = &self.transport {
+            return Arc::clone(transport);
+        }
+        shared_transport(self.proxy(platform), self.timeouts.connect)
+    }
+    /// URL of the VK API method, respects `vk_api_url` override
+    pub fn vk_method_url(&self, method: &str) -> String {
+        let base = self.vk_api_url.as_deref().unwrap_or(VK_API_URL);
+        format!("{}/{}", base.trim_end_matches('/'), method)
+    }
+    /// URL of the Telegram Bot API method, respects `tg_api_url` override
+    pub fn tg_method_url(&self, method: &str) -> String {
+        let base = self.tg_api_url.as_deref().unwrap_or(TG_API_URL);
+        format!(
+            "{}/{}/{}",
+            base.trim_end_matches('/'),
+            self.tg_access_token,
+            method
+        )
+    }
+    /// URL to download a Telegram file by the `file_path` returned from `getFile`, respects `tg_api_url` override
+    pub fn tg_file_url(&self, file_path: &str) -> String {
+        let base = self.tg_api_url.as_deref().unwrap_or(TG_API_URL);
+        format!(
+            "{}/file/{}/{}",
+            base.trim_end_matches('/'),
+            self.tg_access_token,
+            file_path.trim_start_matches('/')
+        )
+    }
+    /// Telegram bot ID, the part of the token before `:` without `bot` prefix
+    pub fn tg_bot_id(&self) -> &str {
+        let token = self.tg_access_token.trim_start_matches("bot");
+        token.split(':').next().unwrap_or(token)
+    }
+    /// Whether VK access token is set
+    pub fn vk_enabled(&self) -> bool {
+        !self.vk_access_token.is_empty()
+    }
+    /// Whether Telegram access token is set
+    pub fn tg_enabled(&self) -> bool {
+        !self.tg_access_token.is_empty()
+    }
+    /// Whether the platform is configured
+    pub fn is_enabled(&self, platform: &Platform) -> bool {
+        match platform {
+            Platform::VK => self.vk_enabled(),
+            Platform::Telegram => self.tg_enabled(),
+        }
+    }
     pub fn check(mut self) -> Self {
-        if self.tg_access_token.is_empty() || self.vk_access_token.is_empty() {
-            panic!("Telegram or VK access token is empty");
+        if !self.vk_enabled() && !self.tg_enabled() {
+            panic!("Telegram and VK access tokens are empty");
         }
-        if !self.tg_access_token.starts_with("bot") {
+        if self.tg_enabled() && !self.tg_access_token.starts_with("bot") {
             panic!("Telegram access token must starts with 'bot'");
         }
-        if self.vk_group_id == 0 || self.vk_group_id.is_negative() {
+        if self.vk_enabled() && (self.vk_group_id == 0 || self.vk_group_id.is_negative()) {
             panic!("VK group ID is empty or invalid");
         }
         if self.vk_api_version.is_empty() {
             self.vk_api_version = "5.199".to_string();
         }
-        if self.callback.is_some() {
-            let callback = self.callback.as_ref().unwrap();
-            if callback.port == 0 {
+        if self.timeouts.longpoll <= self.timeouts.longpoll_wait {
+            panic!("Long poll timeout must be longer than long poll wait");
+        }
+        for proxy in [&self.vk_proxy, &self.tg_proxy].into_iter().flatten() {
+            if let Err(err) = Proxy::parse(proxy) {
+                panic!("Invalid proxy settings: {}", err);
+            }
+        }
+        if let Some(callback) = &self.callback {
+            if callback.bind.is_none() && callback.port == 0 {
                 panic!("Callback port is empty or invalid");
             }
             if callback.callback_url.is_empty() {
                 panic!("Callback URL is empty");
             }
-            if callback.secret.is_empty() {
-                panic!("Callback secret is empty");
+            if self.vk_enabled()
+                && callback.vk_confirmation.is_empty()
+                && callback.vk_register.is_none()
+            {
+                panic!("VK callback confirmation string is empty");
+            }
+            if self.vk_enabled() && callback.vk_secret.is_empty() {
+                panic!("VK callback secret is empty");
+            }
+            if self.tg_enabled() {
+                let token = &callback.tg_secret_token;
+                if token.is_empty() || token.len() > 256 {
+                    panic!("Telegram secret token must be 1-256 characters long");
+                }
+                if !token
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    panic!("Telegram secret token may contain only A-Z, a-z, 0-9, _ and -");
+                }
             }
             if callback.path.is_empty() {
                 panic!("Callback path is empty");
@@ -1,3 +1,4 @@
+use log::error;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -7,7 +8,7 @@ use std::sync::Arc;
 use crate::client::requests::File;
 use crate::structs::keyboard::{self, Keyboard};
 
-use crate::client::api_requests::api_call;
+use crate::client::api_requests::{api_call, ApiError};
 use crate::upload::{
     download_files, send_tg_attachment_files, send_tg_attachments, upload_vk_attachments,
     Attachment,
@@ -15,10 +16,13 @@ use crate::upload::{
 
 use super::config::Config;
 use super::struct_to_vec::{param, struct_to_vec};
-use super::tg::{TGCallbackQuery, TGChosenInlineResult, TGInlineQuery, TGMessage};
+use super::tg::{
+    TGCallbackQuery, TGChatMemberUpdated, TGChosenInlineResult, TGInlineQuery, TGMessage,
+    TGMessageReactionUpdated,
+};
 use super::tg_api::TGSendMessageOptions;
 use super::tg_attachments::TGAttachment;
-use super::vk::{VKMessageEvent, VKMessageNew};
+use super::vk::{VKHistoryLost, VKMessageEvent, VKMessageNew};
 use super::vk_api::VKMessagesSendOptions;
 use super::vk_attachments::VKAttachment;
 
@@ -31,6 +35,10 @@ pub enum Event {
     TGCallbackQuery(TGCallbackQuery),
     TGInlineQuery(TGInlineQuery),
     TGChosenInlineResult(TGChosenInlineResult),
+    TGChatMember(TGChatMemberUpdated),
+    TGMyChatMember(TGChatMemberUpdated),
+    TGMessageReaction(TGMessageReactionUpdated),
+    VKHistoryLost(VKHistoryLost),
     Unknown,
 }
 
@@ -87,6 +95,9 @@ pub enum Platform {
 /// * `InlineQuery` - Inline query event
 /// * `ChosenInlineResult` - Chosen inline result event
 /// * `CallbackQuery` - Callback query event
+/// * `ChatMember` - Chat member status change (Telegram `chat_member` and `my_chat_member`)
+/// * `MessageReaction` - Message reaction change (Telegram `message_reaction`)
+/// * `HistoryLost` - Some updates may be lost (VK longpoll `failed: 1`)
 /// * `Unknown` - Unknown event
 #[derive(Debug, Clone, PartialEq)]
 pub enum EventType {
@@ -95,6 +106,9 @@ pub enum EventType {
     InlineQuery,
     ChosenInlineResult,
     CallbackQuery,
+    ChatMember,
+    MessageReaction,
+    HistoryLost,
     Unknown,
 }
 
@@ -227,7 +241,13 @@ impl MessageBuilder {
         let config = self.config.clone();
         match self.platform {
             Platform::VK => {
-                let attachments = self.make_vk_attachments(config.clone(), peer_id).await;
+                let attachments = match self.make_vk_attachments(config.clone(), peer_id).await {
+                    Ok(attachments) => attachments,
+                    Err(err) => {
+                        error!("Failed to upload attachments to VK: {}", err);
+                        return;
+                    }
+                };
                 let vk_options = self.vk_options.unwrap_or_default();
                 let keyboard = self.keyboard;
                 let attachment = attachments.unwrap_or("".to_string());
@@ -297,30 +317,32 @@ impl MessageBuilder {
                         send_tg_attachments(attachments, &config, peer_id, &self.message).await;
                         return;
                     }
-                    send_tg_attachment_files(files, &config, peer_id, &self.message).await;
+                    if let Err(err) =
+                        send_tg_attachment_files(files, &config, peer_id, &self.message).await
+                    {
+                        error!("Failed to send attachments to Telegram: {}", err);
+                    }
                 });
             }
         }
     }
-    async fn make_vk_attachments(&self, config: Arc<Config>, peer_id: i64) -> Option<String> {
+    async fn make_vk_attachments(
+        &self,
+        config: Arc<Config>,
+        peer_id: i64,
+    ) -> Result<Option<String>, ApiError> {
         let attachments = self.attachments.clone().unwrap_or_default();
         let files = self.files.clone().unwrap_or_default();
         if attachments.is_empty() && files.is_empty() {
-            return None;
+            return Ok(None);
         }
         if !attachments.is_empty() {
-            let attachments = download_files(attachments).await;
-            return Some(
-                upload_vk_attachments(attachments, &config, peer_id)
-                    .await
-                    .unwrap(),
-            );
+            let attachments = download_files(attachments, Platform::VK, &config).await?;
+            return Ok(Some(
+                upload_vk_attachments(attachments, &config, peer_id).await?,
+            ));
         }
-        Some(
-            upload_vk_attachments(files, &config, peer_id)
-                .await
-                .unwrap(),
-        )
+        Ok(Some(upload_vk_attachments(files, &config, peer_id).await?))
     }
 }
 /// Get event from context
@@ -624,13 +646,7 @@ impl UnifyedContext {
     ///use vtg::client::requests::{File, FileType};
     ///ctx.send_attachment_files(
     ///    "пива бы",
-    ///    vec![File {
-    ///        filename: "pivo.jpg".to_string(),
-    ///        content: tokio::fs::read("C:\\Projects\\RustProjects\\vtg\\examples\\pivo2.jpg")
-    ///            .await
-    ///            .unwrap(),
-    ///        ftype: FileType::Photo,
-    ///    }],
+    ///    vec![File::from_path("C:\\Projects\\RustProjects\\vtg\\examples\\pivo2.jpg", FileType::Photo)],
     ///).await;
     /// ```
     ///
@@ -641,6 +657,14 @@ impl UnifyedContext {
         match self.platform {
             Platform::VK => {
                 tokio::task::spawn(async move {
+                    let attachment =
+                        match upload_vk_attachments(attachments, &config, peer_id).await {
+                            Ok(attachment) => attachment,
+                            Err(err) => {
+                                error!("Failed to upload attachments to VK: {}", err);
+                                return;
+                            }
+                        };
                     api_call(
                         Platform::VK,
                         "messages.send",
@@ -648,12 +672,7 @@ impl UnifyedContext {
                             param("peer_id", peer_id.to_string()),
                             param("message", &message_str),
                             param("random_id", "0"),
-                            param(
-                                "attachment",
-                                upload_vk_attachments(attachments, &config, peer_id)
-                                    .await
-                                    .unwrap(),
-                            ),
+                            param("attachment", attachment),
                         ],
                         &config,
                     )
@@ -663,8 +682,16 @@ impl UnifyedContext {
             }
             Platform::Telegram => {
                 tokio::task::spawn(async move {
-                    send_tg_attachment_files(attachments, &config, peer_id, message_str.as_str())
-                        .await;
+                    if let Err(err) = send_tg_attachment_files(
+                        attachments,
+                        &config,
+                        peer_id,
+                        message_str.as_str(),
+                    )
+                    .await
+                    {
+                        error!("Failed to send attachments to Telegram: {}", err);
+                    }
                 });
             }
         }
@@ -701,7 +728,14 @@ impl UnifyedContext {
         match self.platform {
             Platform::VK => {
                 tokio::task::spawn(async move {
-                    let attachments = download_files(attachments).await;
+                    let attachments = download_files(attachments, Platform::VK, &config).await;
+                    let attachments = match attachments {
+                        Ok(attachments) => attachments,
+                        Err(err) => {
+                            error!("Failed to download attachments: {}", err);
+                            return;
+                        }
+                    };
                     api_call(
                         Platform::VK,
                         "messages.send",
@@ -1,10 +1,12 @@
-use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_with::skip_serializing_none;
 
 use std::sync::Arc;
 
 use super::config::Config;
 use super::context::{Event, EventType, Platform, UnifyContext, UnifyedContext};
+use super::tg_api::{TGChatMember, TGReactionType};
 use super::tg_attachments::*;
 
 #[derive(Deserialize, Clone, Debug)]
@@ -20,9 +22,52 @@ pub struct TGUpdate {
     pub inline_query: Option<TGInlineQuery>,
     pub chosen_inline_result: Option<TGChosenInlineResult>,
     pub callback_query: Option<TGCallbackQuery>,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub my_chat_member: Option<TGChatMemberUpdated>,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub chat_member: Option<TGChatMemberUpdated>,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub message_reaction: Option<TGMessageReactionUpdated>,
     pub update_id: i64,
 }
 
+// Update fields which fail to parse become `None` instead of failing the whole update list
+fn deserialize_lenient<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let value = Option::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(value.and_then(|value| T::deserialize(value).ok()))
+}
+
+/// Change of a chat member status (`chat_member` and `my_chat_member` updates)
+#[skip_serializing_none]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TGChatMemberUpdated {
+    pub chat: TGChat,
+    pub from: TGFrom,
+    pub date: i64,
+    pub old_chat_member: TGChatMember,
+    pub new_chat_member: TGChatMember,
+    pub invite_link: Option<serde_json::Value>,
+    pub via_join_request: Option<bool>,
+    pub via_chat_folder_invite_link: Option<bool>,
+}
+
+/// Change of a reaction on a message (`message_reaction` update)
+#[skip_serializing_none]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TGMessageReactionUpdated {
+    pub chat: TGChat,
+    pub message_id: i64,
+    pub user: Option<TGFrom>,
+    pub actor_chat: Option<TGChat>,
+    pub date: i64,
+    pub old_reaction: Vec<TGReactionType>,
+    pub new_reaction: Vec<TGReactionType>,
+}
+
 #[skip_serializing_none]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TGCallbackQuery {
@@ -203,11 +248,53 @@ impl UnifyContext for TGUpdate {
                 (
                     EventType::CallbackQuery,
                     query.data.clone(),
-                    query.message.as_ref().unwrap().chat.id,
-                    query.message.as_ref().unwrap().message_id,
+                    query.message.as_ref().map_or(0, |message| message.chat.id),
+                    query
+                        .message
+                        .as_ref()
+                        .map_or(0, |message| message.message_id),
                     query.from.id,
                 )
             }
+            TGUpdate {
+                my_chat_member: Some(update),
+                ..
+            } => {
+                event = Event::TGMyChatMember(update.clone());
+                (
+                    EventType::ChatMember,
+                    None,
+                    update.chat.id,
+                    0,
+                    update.from.id,
+                )
+            }
+            TGUpdate {
+                chat_member: Some(update),
+                ..
+            } => {
+                event = Event::TGChatMember(update.clone());
+                (
+                    EventType::ChatMember,
+                    None,
+                    update.chat.id,
+                    0,
+                    update.from.id,
+                )
+            }
+            TGUpdate {
+                message_reaction: Some(update),
+                ..
+            } => {
+                event = Event::TGMessageReaction(update.clone());
+                (
+                    EventType::MessageReaction,
+                    None,
+                    update.chat.id,
+                    update.message_id,
+                    update.user.as_ref().map_or(0, |user| user.id),
+                )
+            }
 
             _ => {
                 event = Event::Unknown;
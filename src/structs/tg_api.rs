@@ -1087,13 +1087,11 @@ impl Api {
     pub fn set_chat_photo_file(photo: File, chat_id: i64, config: Arc<Config>) {
         tokio::task::spawn(async move {
             files_request(
-                &format!(
-                    "https://api.telegram.org/{}/setChatPhoto",
-                    config.tg_access_token,
-                ),
+                &config.tg_method_url("setChatPhoto"),
                 &[photo],
                 Some(vec![("chat_id", &chat_id.to_string())]),
                 Platform::Telegram,
+                &config,
             )
             .await
             .unwrap();
@@ -1345,6 +1343,15 @@ pub struct TGFile {
     pub file_path: Option<String>,
 }
 
+impl TGFile {
+    /// URL to download the file from, `None` if Telegram didn't return `file_path`
+    pub fn url(&self, config: &Config) -> Option<String> {
+        self.file_path
+            .as_deref()
+            .map(|file_path| config.tg_file_url(file_path))
+    }
+}
+
 #[skip_serializing_none]
 #[derive(Serialize, Deserialize, Clone, Default, Debug)]
 pub struct TGBanChatMember {
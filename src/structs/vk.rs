@@ -1,3 +1,4 @@
+use log::error;
 use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_with::skip_serializing_none;
@@ -8,7 +9,7 @@ use super::config::Config;
 use super::context::{Event, EventType, Platform, UnifyContext, UnifyedContext};
 use super::vk_attachments::{VKAttachment, unify_attachments};
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct VKGetServer {
     pub key: String,
     pub server: String,
@@ -23,11 +24,21 @@ pub struct VKGetServerResponse {
 #[derive(Deserialize, Debug)]
 pub struct VKGetUpdates {
     pub failed: Option<i16>,
-    #[serde(deserialize_with = "deserialize_ts")]
-    pub ts: String,
+    #[serde(default, deserialize_with = "deserialize_opt_ts")]
+    pub ts: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_updates")]
     pub updates: Option<Vec<VKUpdate>>,
 }
 
+/// Longpoll event history loss, reported when VK returns `failed: 1`
+///
+/// Events between `ts` and `new_ts` may be lost, polling continues from `new_ts`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VKHistoryLost {
+    pub ts: String,
+    pub new_ts: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct VKTs {
     #[serde(deserialize_with = "deserialize_ts")]
@@ -78,12 +89,83 @@ where
 
     deserializer.deserialize_any(TSVisitor)
 }
+
+fn deserialize_opt_ts<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_ts(deserializer).map(Some)
+}
+
+// A malformed update is skipped, so it doesn't stall longpoll on the same ts forever
+fn deserialize_updates<'de, D>(deserializer: D) -> Result<Option<Vec<VKUpdate>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let values = Option::<Vec<serde_json::Value>>::deserialize(deserializer)?;
+    Ok(values.map(|values| {
+        values
+            .into_iter()
+            .filter_map(|value| match VKUpdate::deserialize(&value) {
+                Ok(update) => Some(update),
+                Err(err) => {
+                    error!(
+                        "[LONGPOLL] [VK] Skipping malformed update: {} ({})",
+                        err, value
+                    );
+                    None
+                }
+            })
+            .collect()
+    }))
+}
+
+#[derive(Deserialize)]
+struct RawVKUpdate {
+    r#type: String,
+    group_id: Option<i64>,
+    event_id: Option<String>,
+    secret: Option<String>,
+    object: Option<serde_json::Value>,
+}
+
 #[derive(Deserialize, Clone, Debug)]
+#[serde(try_from = "RawVKUpdate")]
 pub struct VKUpdate {
     pub r#type: String,
+    pub group_id: Option<i64>,
+    pub event_id: Option<String>,
+    pub secret: Option<String>,
     pub object: Option<VKObject>,
 }
 
+// Objects of unsupported event types become `None`, objects of supported ones must be valid
+impl TryFrom<RawVKUpdate> for VKUpdate {
+    type Error = serde_json::Error;
+
+    fn try_from(raw: RawVKUpdate) -> Result<Self, Self::Error> {
+        let object = match (raw.r#type.as_str(), raw.object) {
+            ("message_new", Some(object)) => {
+                Some(VKObject::MessageNew(VKMessageNew::deserialize(object)?))
+            }
+            ("message_event", Some(object)) => {
+                Some(VKObject::MessageEvent(VKMessageEvent::deserialize(object)?))
+            }
+            ("message_new" | "message_event", None) => {
+                return Err(de::Error::missing_field("object"));
+            }
+            _ => None,
+        };
+        Ok(VKUpdate {
+            r#type: raw.r#type,
+            group_id: raw.group_id,
+            event_id: raw.event_id,
+            secret: raw.secret,
+            object,
+        })
+    }
+}
+
 #[derive(Deserialize, Clone, Debug)]
 #[serde(untagged)]
 pub enum VKObject {
@@ -313,3 +395,20 @@ impl UnifyContext for VKUpdate {
         }
     }
 }
+
+impl UnifyContext for VKHistoryLost {
+    fn unify(&self, config: Arc<Config>) -> UnifyedContext {
+        UnifyedContext {
+            text: String::new(),
+            from_id: 0,
+            peer_id: 0,
+            id: 0,
+            r#type: EventType::HistoryLost,
+            platform: Platform::VK,
+            data: String::new(),
+            config,
+            event: Event::VKHistoryLost(self.clone()),
+            attachments: None,
+        }
+    }
+}
@@ -6,7 +6,7 @@ use serde_json::{from_value, Value};
 use serde_with::skip_serializing_none;
 use tokio::task::JoinHandle;
 
-use crate::client::requests::{files_request, FileType};
+use crate::client::requests::{files_request, FileType, HyperRequestError};
 use crate::client::{api_requests::api_call, requests::File};
 use crate::structs::vk::VKMessage;
 use crate::upload::{download_files, Attachment};
@@ -492,7 +492,7 @@ impl Messages {
         .unwrap();
         let val: VKGetUploadServerResponse = from_value(resp).unwrap();
         let upload_url = val.response.upload_url;
-        let server_resp = files_request(&upload_url, &[photo], None, Platform::VK)
+        let server_resp = files_request(&upload_url, &[photo], None, Platform::VK, &config)
             .await
             .unwrap();
         let server_resp: VKMessageChatPhotoUploaded = serde_json::from_str(&server_resp).unwrap();
@@ -504,17 +504,24 @@ impl Messages {
             config,
         );
     }
+    /// Downloads the photo and sets it as chat photo, fails if the photo can't be downloaded
     pub async fn set_chat_photo(
         options: VKMessagesSetChatPhoto,
         photo_url: String,
         config: Arc<Config>,
-    ) {
-        let attachments = download_files(vec![Attachment {
-            url: photo_url,
-            ftype: FileType::Photo,
-        }])
-        .await;
-        Self::set_chat_photo_file(options, attachments[0].clone(), config).await;
+    ) -> Result<(), HyperRequestError> {
+        let attachments = download_files(
+            vec![Attachment {
+                url: photo_url,
+                ftype: FileType::Photo,
+            }],
+            Platform::VK,
+            &config,
+        )
+        .await?;
+        let photo = attachments.into_iter().next().unwrap();
+        Self::set_chat_photo_file(options, photo, config).await;
+        Ok(())
     }
 }
 
@@ -2,3 +2,1175 @@
 async fn requests() {
     assert_eq!("hello".to_string(), "hello".to_string());
 }
+
+#[test]
+fn api_error_from_tg_response() {
+    use crate::client::api_requests::ApiError;
+
+    let response = serde_json::json!({
+        "ok": false,
+        "error_code": 429,
+        "description": "Too Many Requests: retry after 5",
+        "parameters": { "retry_after": 5 }
+    });
+    let error = ApiError::from_tg_response(&response);
+    assert_eq!(error.error_code(), Some(429));
+    assert_eq!(error.retry_after(), Some(5));
+
+    let error = ApiError::from_vk_error(&serde_json::json!({
+        "error_code": 6,
+        "error_msg": "Too many requests per second",
+        "request_params": [{ "key": "method", "value": "messages.send" }]
+    }));
+    assert_eq!(error.error_code(), Some(6));
+}
+
+#[tokio::test]
+async fn retry_delay_follows_policy() {
+    use crate::client::api_requests::{ApiError, retry_delay};
+    use crate::client::requests::{HyperRequestError, request};
+    use crate::structs::config::{Config, RetrySettings};
+    use crate::structs::context::Platform;
+    use std::time::Duration;
+
+    let retry = RetrySettings {
+        jitter: false,
+        max_delay: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let flood = ApiError::from_vk_error(&serde_json::json!({ "error_code": 6 }));
+    assert_eq!(
+        retry_delay(&retry, &Platform::VK, 1, &flood),
+        Some(Duration::from_millis(500))
+    );
+    assert_eq!(
+        retry_delay(&retry, &Platform::VK, 3, &flood),
+        Some(Duration::from_secs(1))
+    );
+    let denied = ApiError::from_vk_error(&serde_json::json!({ "error_code": 901 }));
+    assert_eq!(retry_delay(&retry, &Platform::VK, 1, &denied), None);
+
+    let too_many = ApiError::from_tg_response(&serde_json::json!({
+        "ok": false,
+        "error_code": 429,
+        "parameters": { "retry_after": 5 }
+    }));
+    let jitter = RetrySettings::default();
+    assert_eq!(
+        retry_delay(&jitter, &Platform::Telegram, 1, &too_many),
+        Some(Duration::from_secs(5))
+    );
+    // Waiting longer than `max_delay` isn't allowed, the flood control error is returned
+    assert_eq!(retry_delay(&retry, &Platform::Telegram, 1, &too_many), None);
+
+    for _ in 0..20 {
+        let delay = retry_delay(&jitter, &Platform::VK, 2, &flood).unwrap();
+        assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_secs(1));
+    }
+
+    // Sent requests may have been delivered, so only connection failures are retried by default
+    let timeout = ApiError::Request(HyperRequestError::Timeout(Duration::from_secs(30)));
+    assert_eq!(retry_delay(&retry, &Platform::VK, 1, &timeout), None);
+    let retry_sent = RetrySettings {
+        retry_sent_requests: true,
+        ..retry.clone()
+    };
+    assert!(retry_delay(&retry_sent, &Platform::VK, 1, &timeout).is_some());
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/method/users.get", listener.local_addr().unwrap());
+    drop(listener);
+    let config = Config {
+        vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+        ..Default::default()
+    };
+    let refused = request(&url, "", vec![], Platform::VK, &config)
+        .await
+        .unwrap_err();
+    assert!(refused.is_connect());
+    let refused = ApiError::Request(refused);
+    assert!(retry_delay(&retry, &Platform::VK, 1, &refused).is_some());
+}
+
+#[test]
+fn token_bucket_and_rate_limit_buckets() {
+    use crate::client::rate_limit::{TokenBucket, buckets_for};
+    use crate::structs::config::{Config, RateLimitSettings};
+    use crate::structs::context::Platform;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    let mut bucket = TokenBucket::new(2, Duration::from_secs(1));
+    let now = Instant::now();
+    assert_eq!(bucket.reserve(now), Duration::ZERO);
+    assert_eq!(bucket.reserve(now), Duration::ZERO);
+    assert_eq!(bucket.reserve(now), Duration::from_millis(500));
+    assert_eq!(bucket.reserve(now), Duration::from_secs(1));
+    // Tokens are refilled up to capacity only
+    let later = now + Duration::from_secs(10);
+    assert_eq!(bucket.reserve(later), Duration::ZERO);
+    assert_eq!(bucket.reserve(later), Duration::ZERO);
+    assert_eq!(bucket.reserve(later), Duration::from_millis(500));
+
+    let config = Config {
+        vk_access_token: "VK".to_string(),
+        tg_access_token: "TG".to_string(),
+        ..Default::default()
+    };
+    let settings = RateLimitSettings::default();
+    let keys = |platform: Platform, method: &str, chat_id: Option<&str>, settings| {
+        buckets_for(settings, &platform, method, chat_id, &config)
+            .into_iter()
+            .map(|(key, amount, period)| (key, amount, period.as_secs()))
+            .collect::<Vec<_>>()
+    };
+    let key = |key: &str, amount: u32, period: u64| (key.to_string(), amount, period);
+
+    assert_eq!(
+        keys(Platform::VK, "users.get", None, &settings),
+        [key("vk:VK", 20, 1)]
+    );
+    assert!(keys(Platform::Telegram, "getMe", None, &settings).is_empty());
+    assert_eq!(
+        keys(Platform::Telegram, "sendMessage", Some("5"), &settings),
+        [key("tg:TG", 30, 1), key("tg:TG:5", 1, 1)]
+    );
+    assert_eq!(
+        keys(Platform::Telegram, "copyMessage", Some("-100"), &settings),
+        [
+            key("tg:TG", 30, 1),
+            key("tg:TG:-100", 1, 1),
+            key("tg:TG:-100:minute", 20, 60)
+        ]
+    );
+    assert_eq!(
+        keys(Platform::Telegram, "sendPhoto", Some("@channel"), &settings).len(),
+        3
+    );
+
+    // Zero disables the limit
+    let settings = RateLimitSettings {
+        tg_chat_messages_per_second: 0,
+        tg_group_messages_per_minute: 0,
+        ..Default::default()
+    };
+    assert_eq!(
+        keys(Platform::Telegram, "sendMessage", Some("-100"), &settings),
+        [key("tg:TG", 30, 1)]
+    );
+}
+
+#[test]
+fn vk_execute_batch() {
+    use crate::client::batch::{execute_code, split_execute_response};
+
+    let params = vec![("user_ids".to_string(), "1".to_string())];
+    assert_eq!(
+        execute_code(&[("users.get", &params), ("groups.getById", &[])]),
+        r#"return [API.users.get({"user_ids":"1"}),API.groups.getById({})];"#
+    );
+
+    let response = serde_json::json!({
+        "response": [false, [{ "id": 1 }]],
+        "execute_errors": [{ "method": "messages.send", "error_code": 901, "error_msg": "Can't send messages" }]
+    });
+    let results = split_execute_response(&["messages.send", "users.get"], &response);
+    assert_eq!(results[0].as_ref().unwrap_err().error_code(), Some(901));
+    assert_eq!(results[1].as_ref().unwrap()["response"][0]["id"], 1);
+}
+
+#[tokio::test]
+async fn callback_service_validates_vk_requests() {
+    use crate::server::CallbackService;
+    use crate::structs::config::{CallbackSettings, Config};
+    use crate::structs::middleware::MiddlewareChain;
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+    use hyper::Request;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let rejected = Arc::new(AtomicUsize::new(0));
+    let rejected_clone = Arc::clone(&rejected);
+    let config = Config {
+        vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+        vk_group_id: 1,
+        callback: Some(CallbackSettings {
+            port: 1234,
+            callback_url: "https://example.com".to_string(),
+            path: "bot".to_string(),
+            vk_confirmation: "confirm".to_string(),
+            vk_secret: "secret".to_string(),
+            on_rejected: Some(Arc::new(move |_| {
+                rejected_clone.fetch_add(1, Ordering::SeqCst);
+            })),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let service = CallbackService::start(vec![(MiddlewareChain::new(), config)])
+        .await
+        .unwrap();
+    let request = |path: &str, body: &'static str| {
+        Request::post(path)
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    };
+
+    let response = service
+        .handle(request(
+            "/bot/vk",
+            r#"{"type":"confirmation","group_id":1}"#,
+        ))
+        .await;
+    assert_eq!(response.status(), 200);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "confirm");
+
+    let response = service
+        .handle(request(
+            "/bot/vk",
+            r#"{"type":"message_new","secret":"wrong","object":{"message":{"text":"","from_id":1,"peer_id":1,"id":1}}}"#,
+        ))
+        .await;
+    assert_eq!(response.status(), 403);
+    let response = service.handle(request("/bot/vk", "not json")).await;
+    assert_eq!(response.status(), 400);
+    assert_eq!(rejected.load(Ordering::SeqCst), 1);
+    // Supported event with an object that doesn't match it is rejected, not skipped as unknown
+    let response = service
+        .handle(request(
+            "/bot/vk",
+            r#"{"type":"message_new","secret":"secret","object":{"message":{"text":1}}}"#,
+        ))
+        .await;
+    assert_eq!(response.status(), 400);
+    assert_eq!(rejected.load(Ordering::SeqCst), 2);
+    let response = service
+        .handle(
+            Request::post("/bot/vk")
+                .body(Full::new(Bytes::from(vec![b' '; 2 * 1024 * 1024])))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 413);
+    let response = service.handle(request("/bot/telegram", "{}")).await;
+    assert_eq!(response.status(), 404);
+    let response = service
+        .handle(request(
+            "/bot/vk",
+            r#"{"type":"wall_post_new","secret":"secret"}"#,
+        ))
+        .await;
+    assert_eq!(response.status(), 200);
+
+    service.shutdown(std::time::Duration::from_secs(1)).await;
+}
+
+#[tokio::test]
+async fn callback_service_start_returns_confirmation_error() {
+    use crate::client::api_requests::ApiError;
+    use crate::server::CallbackService;
+    use crate::structs::config::{CallbackSettings, Config, VKCallbackRegistration};
+    use crate::structs::middleware::MiddlewareChain;
+    use std::collections::HashMap;
+
+    let (base, _) = stub_server(HashMap::from([(
+        "/method/groups.getCallbackConfirmationCode",
+        http_response(
+            "200 OK",
+            &["Content-Type: application/json"],
+            br#"{"error":{"error_code":5,"error_msg":"User authorization failed"}}"#,
+        ),
+    )]))
+    .await;
+    let config = Config {
+        vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+        vk_group_id: 1,
+        vk_api_url: Some(format!("{}/method", base)),
+        callback: Some(CallbackSettings {
+            port: 1234,
+            callback_url: "https://example.com".to_string(),
+            path: "bot".to_string(),
+            vk_secret: "secret".to_string(),
+            vk_register: Some(VKCallbackRegistration::default()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let err = CallbackService::start(vec![(MiddlewareChain::new(), config)])
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, ApiError::VK { error_code: 5, .. }),
+        "{:?}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn callback_service_selects_account() {
+    use crate::server::CallbackService;
+    use crate::structs::config::{CallbackSettings, Config};
+    use crate::structs::middleware::MiddlewareChain;
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+    use hyper::Request;
+
+    let config = |name: &str, group_id: i64| Config {
+        name: name.to_string(),
+        vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+        vk_group_id: group_id,
+        callback: Some(CallbackSettings {
+            port: 1234,
+            callback_url: "https://example.com".to_string(),
+            path: format!("{}-path", name),
+            vk_confirmation: format!("{}-confirm", name),
+            vk_secret: "secret".to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let service = CallbackService::start(vec![
+        (MiddlewareChain::new(), config("first", 1)),
+        (MiddlewareChain::new(), config("second", 2)),
+    ])
+    .await
+    .unwrap();
+    let confirmation = |group_id: i64| {
+        Request::post("/anywhere")
+            .body(Full::new(Bytes::from(format!(
+                r#"{{"type":"confirmation","group_id":{}}}"#,
+                group_id
+            ))))
+            .unwrap()
+    };
+
+    let response = service.handle_vk_for("second", confirmation(2)).await;
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "second-confirm");
+    let response = service.handle_vk_for("first-path", confirmation(1)).await;
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "first-confirm");
+    let response = service.handle_vk_for("third", confirmation(1)).await;
+    assert_eq!(response.status(), 404);
+
+    // Tower service routes by path like the hyper one
+    let request = Request::post("/second-path/vk")
+        .body(Full::new(Bytes::from(
+            r#"{"type":"confirmation","group_id":2}"#,
+        )))
+        .unwrap();
+    let response = tower_service::Service::call(&mut service.clone(), request)
+        .await
+        .unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "second-confirm");
+
+    service.shutdown(std::time::Duration::from_secs(1)).await;
+}
+
+#[test]
+fn dedup_cache_expires_and_evicts() {
+    use crate::dispatcher::DedupCache;
+    use crate::structs::config::DedupSettings;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    let mut cache = DedupCache::new(&DedupSettings {
+        capacity: 2,
+        ttl: Duration::from_secs(10),
+    });
+    let start = Instant::now();
+    assert!(!cache.is_duplicate("vk:1", start));
+    assert!(cache.is_duplicate("vk:1", start + Duration::from_secs(5)));
+    assert!(!cache.is_duplicate("tg:1", start));
+
+    // IDs older than TTL are forgotten
+    assert!(!cache.is_duplicate("vk:1", start + Duration::from_secs(10)));
+
+    // Oldest ID is evicted when the cache is full
+    let later = start + Duration::from_secs(11);
+    assert!(!cache.is_duplicate("vk:2", later));
+    assert!(!cache.is_duplicate("vk:3", later));
+    assert!(cache.is_duplicate("vk:3", later));
+    assert!(!cache.is_duplicate("vk:2", later));
+}
+
+#[tokio::test]
+async fn send_unique_keeps_platforms_apart() {
+    use crate::dispatcher::Dispatcher;
+    use crate::structs::config::{Config, DedupSettings};
+    use crate::structs::context::{Platform, UnifyContext};
+    use crate::structs::middleware::MiddlewareChain;
+    use crate::structs::vk::VKHistoryLost;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+    let config = Arc::new(Config {
+        vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+        dedup: Some(DedupSettings::default()),
+        ..Default::default()
+    });
+    let mut middleware = MiddlewareChain::new();
+    middleware.add_middleware(|ctx| {
+        Box::pin(async move {
+            PROCESSED.fetch_add(1, Ordering::SeqCst);
+            ctx
+        })
+    });
+    let dispatcher = Dispatcher::start(middleware, &config);
+    let update = |platform: Platform| {
+        let mut update = VKHistoryLost {
+            ts: "1".to_string(),
+            new_ts: "2".to_string(),
+        }
+        .unify(config.clone());
+        update.platform = platform;
+        update
+    };
+
+    let tx = dispatcher.sender();
+    tx.send_unique(Some("1"), update(Platform::VK))
+        .await
+        .unwrap();
+    tx.send_unique(Some("1"), update(Platform::Telegram))
+        .await
+        .unwrap();
+    tx.send_unique(Some("1"), update(Platform::VK))
+        .await
+        .unwrap();
+    dispatcher.shutdown(std::time::Duration::from_secs(1)).await;
+    assert_eq!(PROCESSED.load(Ordering::SeqCst), 2);
+}
+
+// Update with the IDs set, the event itself doesn't matter for dispatching
+fn test_update(id: i64, peer_id: i64, from_id: i64) -> crate::structs::context::UnifyedContext {
+    use crate::structs::config::Config;
+    use crate::structs::context::UnifyContext;
+    use crate::structs::vk::VKHistoryLost;
+
+    let mut update = VKHistoryLost {
+        ts: "1".to_string(),
+        new_ts: "2".to_string(),
+    }
+    .unify(std::sync::Arc::new(Config::default()));
+    update.id = id;
+    update.peer_id = peer_id;
+    update.from_id = from_id;
+    update
+}
+
+#[tokio::test]
+async fn update_queue_overflow_policies() {
+    use crate::dispatcher::UpdateQueue;
+    use crate::structs::config::QueueOverflow;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    let drop_oldest = UpdateQueue::new(2, QueueOverflow::DropOldest);
+    let drop_newest = UpdateQueue::new(2, QueueOverflow::DropNewest);
+    for id in 1..=3 {
+        drop_oldest.push(test_update(id, 1, 1)).await.unwrap();
+        drop_newest.push(test_update(id, 1, 1)).await.unwrap();
+    }
+    assert_eq!(drop_oldest.pop().await.unwrap().id, 2);
+    assert_eq!(drop_oldest.pop().await.unwrap().id, 3);
+    assert_eq!(drop_newest.pop().await.unwrap().id, 1);
+    assert_eq!(drop_newest.pop().await.unwrap().id, 2);
+
+    // Blocked push completes when a slot is freed
+    let block = Arc::new(UpdateQueue::new(1, QueueOverflow::Block));
+    block.push(test_update(1, 1, 1)).await.unwrap();
+    let block_clone = Arc::clone(&block);
+    let blocked = tokio::spawn(async move { block_clone.push(test_update(2, 1, 1)).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!blocked.is_finished());
+    assert_eq!(block.pop().await.unwrap().id, 1);
+    timeout(Duration::from_secs(1), blocked)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(block.pop().await.unwrap().id, 2);
+
+    // Closing wakes blocked push, which gets the update back, queued updates are still popped
+    block.push(test_update(3, 1, 1)).await.unwrap();
+    let block_clone = Arc::clone(&block);
+    let blocked = tokio::spawn(async move { block_clone.push(test_update(4, 1, 1)).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    block.close();
+    let rejected = timeout(Duration::from_secs(1), blocked)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(rejected.unwrap_err().id, 4);
+    assert_eq!(block.pop().await.unwrap().id, 3);
+    assert!(block.pop().await.is_none());
+}
+
+#[tokio::test]
+async fn per_chat_dispatch_keeps_chat_order() {
+    use crate::dispatcher::{Dispatcher, queue_index};
+    use crate::structs::config::{Config, DispatchMode, DispatchSettings};
+    use crate::structs::middleware::MiddlewareChain;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    // Updates without chat, like VK `message_event` in some cases, are assigned by sender
+    assert_eq!(queue_index(&test_update(1, 0, 6), 4), 2);
+    assert_eq!(
+        queue_index(&test_update(1, 0, 6), 4),
+        queue_index(&test_update(2, 6, 1), 4)
+    );
+    assert_eq!(queue_index(&test_update(1, -7, 1), 4), 3);
+    assert_eq!(queue_index(&test_update(1, 7, 1), 1), 0);
+
+    static PROCESSED: Mutex<Vec<(i64, i64)>> = Mutex::new(Vec::new());
+    let config = Config {
+        dispatch: DispatchSettings {
+            workers: 4,
+            mode: DispatchMode::PerChat,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut middleware = MiddlewareChain::new();
+    middleware.add_middleware(|ctx| {
+        Box::pin(async move {
+            // Earlier updates take longer, so they would be overtaken by a free worker
+            tokio::time::sleep(Duration::from_millis(10 - ctx.id as u64 / 3)).await;
+            PROCESSED.lock().unwrap().push((ctx.peer_id, ctx.id));
+            ctx
+        })
+    });
+    let dispatcher = Dispatcher::start(middleware, &config);
+    let tx = dispatcher.sender();
+    for id in 0..30 {
+        tx.send(test_update(id, id % 3 + 1, 0)).await.unwrap();
+    }
+    dispatcher.shutdown(Duration::from_secs(5)).await;
+
+    let processed = PROCESSED.lock().unwrap();
+    assert_eq!(processed.len(), 30);
+    for chat in 1..=3 {
+        let ids: Vec<i64> = processed
+            .iter()
+            .filter(|(peer_id, _)| *peer_id == chat)
+            .map(|(_, id)| *id)
+            .collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", ids);
+    }
+}
+
+#[test]
+fn file_offset_store_round_trip() {
+    use crate::client::offset_store::{FileOffsetStore, OffsetStore};
+    use crate::structs::vk::VKGetServer;
+
+    let path = std::env::temp_dir().join(format!("vtg-offsets-{}.json", std::process::id()));
+    let store = FileOffsetStore::open(&path).unwrap();
+    assert_eq!(store.tg_offset("123"), None);
+    store.set_tg_offset("123", 42);
+    store.set_vk_state(
+        1,
+        &VKGetServer {
+            key: "key".to_string(),
+            server: "https://lp.vk.com/wh1".to_string(),
+            ts: "10".to_string(),
+        },
+    );
+
+    let store = FileOffsetStore::open(&path).unwrap();
+    assert_eq!(store.tg_offset("123"), Some(42));
+    assert_eq!(store.vk_state(1).unwrap().ts, "10");
+    std::fs::remove_file(path).unwrap();
+}
+
+// Raw HTTP response with `Content-Length` set
+fn http_response(status: &str, headers: &[&str], body: &[u8]) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    for header in headers {
+        response.push_str(header);
+        response.push_str("\r\n");
+    }
+    response.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+// Request received by the stub server, head with request line and headers, and body
+type StubRequest = (String, Vec<u8>);
+
+// Answers HTTP/1.1 requests on the connection with responses by path (without query), 404 for unknown paths.
+// Only `Content-Length` request bodies are read, connection is kept open until the client closes it
+async fn serve_stub<S>(
+    mut stream: S,
+    routes: &std::collections::HashMap<&'static str, Vec<u8>>,
+    requests: &tokio::sync::mpsc::UnboundedSender<StubRequest>,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let head_end = loop {
+            if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end + 4;
+            }
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            }
+        };
+        let head = String::from_utf8_lossy(&buffer[..head_end]).to_string();
+        let content_length: usize = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .map_or(0, |(_, value)| value.trim().parse().unwrap());
+        while buffer.len() < head_end + content_length {
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            }
+        }
+        let body = buffer[head_end..head_end + content_length].to_vec();
+        buffer.drain(..head_end + content_length);
+
+        let target = head.split_whitespace().nth(1).unwrap_or("/");
+        let path = target.split('?').next().unwrap();
+        let not_found = http_response("404 Not Found", &[], b"not found");
+        let response = routes.get(path).unwrap_or(&not_found).clone();
+        requests.send((head, body)).ok();
+        if stream.write_all(&response).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Local HTTP server with responses by path, returns its base URL and received requests.
+// Server runs until the test ends
+async fn stub_server(
+    routes: std::collections::HashMap<&'static str, Vec<u8>>,
+) -> (String, tokio::sync::mpsc::UnboundedReceiver<StubRequest>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let routes = std::sync::Arc::new(routes);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let routes = std::sync::Arc::clone(&routes);
+            let tx = tx.clone();
+            tokio::spawn(async move { serve_stub(stream, &routes, &tx).await });
+        }
+    });
+    (url, rx)
+}
+
+#[tokio::test]
+async fn requests_go_through_socks5_proxy() {
+    use crate::client::requests::request;
+    use crate::structs::config::{Config, ProxySettings};
+    use crate::structs::context::Platform;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, mut requests) = tokio::sync::mpsc::unbounded_channel();
+    let proxy = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut greeting = [0u8; 4];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [5, 2, 0, 2]);
+        stream.write_all(&[5, 2]).await.unwrap();
+        let mut auth = [0u8; 11];
+        stream.read_exact(&mut auth).await.unwrap();
+        assert_eq!(&auth, b"\x01\x04user\x04pass");
+        stream.write_all(&[1, 0]).await.unwrap();
+        let mut connect = [0u8; 5];
+        stream.read_exact(&mut connect).await.unwrap();
+        assert_eq!(connect, [5, 1, 0, 3, 16]);
+        let mut target = [0u8; 18];
+        stream.read_exact(&mut target).await.unwrap();
+        assert_eq!(&target, b"api.telegram.org\x00\x50");
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        // Tunnel is established, the proxy answers as the target server
+        let routes = HashMap::from([("/botTOKEN/getMe", http_response("200 OK", &[], b"ok"))]);
+        serve_stub(stream, &routes, &tx).await;
+    });
+
+    let config = Config {
+        tg_access_token: "botTOKEN".to_string(),
+        tg_proxy: Some(ProxySettings {
+            url: format!("socks5://127.0.0.1:{}", port),
+            username: "user".to_string(),
+            password: "pass".to_string(),
+            no_proxy: vec!["localhost".to_string()],
+        }),
+        ..Default::default()
+    };
+    let response = request(
+        "http://api.telegram.org/botTOKEN/getMe",
+        "",
+        vec![],
+        Platform::Telegram,
+        &config,
+    )
+    .await
+    .unwrap();
+    assert_eq!(response, "ok");
+    let (head, _) = requests.recv().await.unwrap();
+    assert!(head.starts_with("POST /botTOKEN/getMe HTTP/1.1\r\n"));
+    // Pooled connection stays open, so the proxy is stopped
+    proxy.abort();
+}
+
+#[tokio::test]
+async fn hung_request_times_out() {
+    use crate::client::requests::{HyperRequestError, request_with_status};
+    use crate::structs::config::{Config, TimeoutSettings};
+    use crate::structs::context::Platform;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    // Body is never sent
+    let (url, _) = stub_server(HashMap::from([(
+        "/method/users.get",
+        b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n".to_vec(),
+    )]))
+    .await;
+
+    let config = Config {
+        vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+        timeouts: TimeoutSettings {
+            request: Duration::from_millis(100),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let url = format!("{}/method/users.get", url);
+    let result = request_with_status(&url, "", vec![], Platform::VK, &config).await;
+    assert!(matches!(
+        result,
+        Err(HyperRequestError::Timeout(timeout)) if timeout == Duration::from_millis(100)
+    ));
+}
+
+#[tokio::test]
+async fn files_request_streams_multipart_body() {
+    use crate::client::requests::{File, FileType, files_request};
+    use crate::structs::config::Config;
+    use crate::structs::context::Platform;
+    use std::collections::HashMap;
+
+    let (url, mut requests) = stub_server(HashMap::from([(
+        "/upload",
+        http_response("200 OK", &[], b"ok"),
+    )]))
+    .await;
+    let url = format!("{}/upload", url);
+
+    let config = Config {
+        tg_access_token: "botTOKEN".to_string(),
+        ..Default::default()
+    };
+    let files = [
+        File::from_bytes("a.txt", "first", FileType::Document),
+        File::from_reader("b.txt", &b"second"[..], Some(6), FileType::Document),
+    ];
+    let response = files_request(&url, &files, None, Platform::VK, &config)
+        .await
+        .unwrap();
+    assert_eq!(response, "ok");
+
+    let (head, body) = requests.recv().await.unwrap();
+    assert!(
+        head.to_lowercase().contains("content-length:"),
+        "request must have content-length"
+    );
+    let boundary = head
+        .split("boundary=")
+        .nth(1)
+        .unwrap()
+        .lines()
+        .next()
+        .unwrap()
+        .to_string();
+    let expected = format!(
+        "--{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+         Content-Type: text/plain\r\n\r\nfirst\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"file1\"; filename=\"b.txt\"\r\n\
+         Content-Type: text/plain\r\n\r\nsecond\r\n\
+         --{b}--\r\n",
+        b = boundary
+    );
+    assert_eq!(String::from_utf8(body).unwrap(), expected);
+
+    // Readers are consumed by the upload
+    assert!(
+        files_request(&url, &files[1..], None, Platform::VK, &config)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn download_follows_redirects_and_enforces_limits() {
+    use crate::client::requests::{HyperRequestError, download};
+    use crate::structs::config::{Config, DownloadSettings};
+    use crate::structs::context::Platform;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    let (base, _) = stub_server(HashMap::from([
+        (
+            "/files/redirect",
+            http_response("302 Found", &["Location: cat.jpg"], b""),
+        ),
+        (
+            "/files/cat.jpg",
+            http_response("200 OK", &["Content-Type: image/jpeg"], b"hello world"),
+        ),
+    ]))
+    .await;
+
+    let config = Config {
+        vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+        ..Default::default()
+    };
+    let progress = Arc::new(AtomicU64::new(0));
+    let progress_clone = Arc::clone(&progress);
+    let settings = DownloadSettings {
+        progress: Some(Arc::new(move |downloaded, total| {
+            assert_eq!(total, Some(11));
+            progress_clone.store(downloaded, Ordering::SeqCst);
+        })),
+        ..Default::default()
+    };
+    let mut content = Vec::new();
+    let url = format!("{}/files/redirect", base);
+    let file = download(&url, &mut content, Platform::VK, &config, &settings)
+        .await
+        .unwrap();
+    assert_eq!(content, b"hello world");
+    assert_eq!(file.url, format!("{}/files/cat.jpg", base));
+    assert_eq!(file.content_type.as_deref(), Some("image/jpeg"));
+    assert_eq!(progress.load(Ordering::SeqCst), 11);
+
+    let url = format!("{}/missing", base);
+    let result = download(&url, &mut Vec::new(), Platform::VK, &config, &settings).await;
+    assert!(matches!(result, Err(HyperRequestError::StatusError(status)) if status == 404));
+
+    let settings = DownloadSettings {
+        max_size: 5,
+        ..Default::default()
+    };
+    let url = format!("{}/files/cat.jpg", base);
+    let result = download(&url, &mut Vec::new(), Platform::VK, &config, &settings).await;
+    assert!(matches!(
+        result,
+        Err(HyperRequestError::SizeLimitExceeded(5))
+    ));
+}
+
+#[tokio::test]
+async fn get_file_detects_type_and_filename() {
+    use crate::client::mime::content_disposition_filename;
+    use crate::client::requests::{FileType, get_file};
+    use crate::structs::config::Config;
+    use crate::structs::context::Platform;
+    use std::collections::HashMap;
+
+    assert_eq!(
+        content_disposition_filename("attachment; filename=\"../cat.jpg\""),
+        Some("cat.jpg".to_string())
+    );
+
+    let (base, _) = stub_server(HashMap::from([
+        (
+            "/files/download.php",
+            http_response(
+                "200 OK",
+                &["Content-Type: application/octet-stream"],
+                b"\xFF\xD8\xFF\xE0JFIF",
+            ),
+        ),
+        (
+            "/files/get",
+            http_response(
+                "200 OK",
+                &[
+                    "Content-Type: image/png; charset=binary",
+                    "Content-Disposition: attachment; filename=\"x.png\"; filename*=UTF-8''%D0%BA%D0%BE%D1%82",
+                ],
+                b"\x89PNG\r\n\x1A\n",
+            ),
+        ),
+    ]))
+    .await;
+
+    let config = Config {
+        vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+        ..Default::default()
+    };
+    let url = format!("{}/files/download.php?id=1", base);
+    let file = get_file(&url, Platform::VK, &config).await.unwrap();
+    assert_eq!(file.filename, "download.jpg");
+    assert_eq!(file.mime_type.as_deref(), Some("image/jpeg"));
+    assert_eq!(file.ftype, FileType::Photo);
+
+    let url = format!("{}/files/get", base);
+    let file = get_file(&url, Platform::VK, &config).await.unwrap();
+    assert_eq!(file.filename, "кот.png");
+    assert_eq!(file.mime_type.as_deref(), Some("image/png"));
+    assert_eq!(file.ftype, FileType::Photo);
+}
+
+#[tokio::test]
+async fn vk_longpoll_handles_failed_codes() {
+    use crate::client::get_vk_updates;
+    use crate::structs::config::Config;
+    use crate::structs::context::Event;
+    use crate::structs::vk::VKGetServer;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    let json = |body: &str| {
+        http_response(
+            "200 OK",
+            &["Content-Type: application/json"],
+            body.as_bytes(),
+        )
+    };
+    let (base, _) = stub_server(HashMap::from([
+        ("/failed1", json(r#"{"failed":1,"ts":"30"}"#)),
+        ("/failed2", json(r#"{"failed":2}"#)),
+        ("/failed3", json(r#"{"failed":3}"#)),
+        (
+            "/malformed",
+            json(
+                r#"{"ts":"31","updates":[{"type":"message_new","object":{"message":{"text":1}}},{"type":"message_new","object":{"message":{"text":"hi","from_id":1,"peer_id":2,"id":3}}}]}"#,
+            ),
+        ),
+        (
+            "/method/groups.getLongPollServer",
+            json(r#"{"response":{"key":"new_key","server":"https://lp.vk.com/new","ts":"50"}}"#),
+        ),
+    ]))
+    .await;
+    let config = Arc::new(Config {
+        vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+        vk_group_id: 1,
+        vk_api_url: Some(format!("{}/method", base)),
+        ..Default::default()
+    });
+    let longpoll = |path: &str, ts: &str| VKGetServer {
+        key: "key".to_string(),
+        server: format!("{}{}", base, path),
+        ts: ts.to_string(),
+    };
+
+    // History is lost, polling continues from the new ts and the loss is reported
+    let mut server = longpoll("/failed1", "10");
+    let updates = get_vk_updates(&mut server, config.clone()).await;
+    assert_eq!(server.ts, "30");
+    assert_eq!(server.key, "key");
+    assert_eq!(updates.len(), 1);
+    assert!(updates[0].0.is_none());
+    assert!(matches!(
+        &updates[0].1.event,
+        Event::VKHistoryLost(lost) if lost.ts == "10" && lost.new_ts == "30"
+    ));
+
+    // Key expired, only key and server are replaced
+    let mut server = longpoll("/failed2", "30");
+    assert!(get_vk_updates(&mut server, config.clone()).await.is_empty());
+    assert_eq!(server.key, "new_key");
+    assert_eq!(server.server, "https://lp.vk.com/new");
+    assert_eq!(server.ts, "30");
+
+    // Key and ts are lost, everything is replaced
+    let mut server = longpoll("/failed3", "30");
+    assert!(get_vk_updates(&mut server, config.clone()).await.is_empty());
+    assert_eq!(server.key, "new_key");
+    assert_eq!(server.ts, "50");
+
+    // Malformed update is skipped, the rest of the batch is processed and ts moves on
+    let mut server = longpoll("/malformed", "30");
+    let updates = get_vk_updates(&mut server, config.clone()).await;
+    assert_eq!(server.ts, "31");
+    assert_eq!(updates.len(), 1);
+    assert_eq!(updates[0].1.text, "hi");
+}
+
+#[tokio::test]
+async fn upload_vk_attachments_returns_errors() {
+    use crate::client::api_requests::ApiError;
+    use crate::client::requests::{File, FileType};
+    use crate::structs::config::Config;
+    use crate::upload::upload_vk_attachments;
+    use std::collections::HashMap;
+
+    let json = |body: &str| {
+        http_response(
+            "200 OK",
+            &["Content-Type: application/json"],
+            body.as_bytes(),
+        )
+    };
+    let (upload, _) = stub_server(HashMap::from([(
+        "/upload",
+        json(r#"{"error":"ERR_UPLOAD_BAD_IMAGE_SIZE"}"#),
+    )]))
+    .await;
+    let (base, _) = stub_server(HashMap::from([(
+        "/method/photos.getMessagesUploadServer",
+        json(&format!(
+            r#"{{"response":{{"upload_url":"{}/upload"}}}}"#,
+            upload
+        )),
+    )]))
+    .await;
+    let config = Config {
+        vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+        vk_group_id: 1,
+        vk_api_url: Some(format!("{}/method", base)),
+        ..Default::default()
+    };
+
+    // Missing file is reported instead of panicking
+    let missing = File::from_path("/nonexistent/photo.jpg", FileType::Photo);
+    let err = upload_vk_attachments(vec![missing], &config, 1)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ApiError::Request(_)), "{:?}", err);
+
+    // Error reply of the upload server is returned instead of a parse panic
+    let photo = File::from_bytes("photo.jpg", b"jpeg".to_vec(), FileType::Photo);
+    let err = upload_vk_attachments(vec![photo], &config, 1)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&err, ApiError::Upload(error) if error == "ERR_UPLOAD_BAD_IMAGE_SIZE"),
+        "{:?}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn longpoll_shutdown_doesnt_wait_for_blocked_poller() {
+    use crate::client::offset_store::{MemoryOffsetStore, OffsetStore};
+    use crate::client::start_longpoll_clients_with_shutdown;
+    use crate::structs::config::{Config, DispatchSettings, QueueOverflow};
+    use crate::structs::middleware::MiddlewareChain;
+    use crate::structs::vk::VKGetServer;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let json = |body: &str| {
+        http_response(
+            "200 OK",
+            &["Content-Type: application/json"],
+            body.as_bytes(),
+        )
+    };
+    let message = |id: i64| {
+        format!(
+            r#"{{"type":"message_new","event_id":"{}","object":{{"message":{{"text":"","from_id":1,"peer_id":1,"id":{}}}}}}}"#,
+            id, id
+        )
+    };
+    let (base, _) = stub_server(HashMap::from([(
+        "/lp",
+        json(&format!(
+            r#"{{"ts":"2","updates":[{},{},{}]}}"#,
+            message(1),
+            message(2),
+            message(3)
+        )),
+    )]))
+    .await;
+    // Stored longpoll state lets polling start without calling `groups.getLongPollServer`
+    let store = MemoryOffsetStore::new();
+    store.set_vk_state(
+        1,
+        &VKGetServer {
+            key: "key".to_string(),
+            server: format!("{}/lp", base),
+            ts: "1".to_string(),
+        },
+    );
+    let config = Config {
+        vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+        vk_group_id: 1,
+        shutdown_timeout: Some(Duration::from_millis(200)),
+        dispatch: DispatchSettings {
+            workers: 1,
+            queue_capacity: 1,
+            overflow: QueueOverflow::Block,
+            ..Default::default()
+        },
+        offset_store: Some(Arc::new(store)),
+        ..Default::default()
+    };
+    // Handler never finishes, so the poller stays blocked on the third update
+    let mut middleware = MiddlewareChain::new();
+    middleware.add_middleware(|_| Box::pin(std::future::pending()));
+
+    let shutdown = tokio::time::sleep(Duration::from_millis(200));
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        start_longpoll_clients_with_shutdown(vec![(middleware, config)], shutdown),
+    )
+    .await
+    .expect("shutdown didn't finish");
+}
+
+#[test]
+fn config_debug_hides_secrets() {
+    use crate::structs::config::{CallbackSettings, Config, ProxySettings};
+
+    let config = Config {
+        vk_access_token: "VK_ACCESS_TOKEN".to_string(),
+        tg_access_token: "TG_ACCESS_TOKEN".to_string(),
+        callback: Some(CallbackSettings {
+            vk_secret: "VK_SECRET".to_string(),
+            tg_secret_token: "TG_SECRET".to_string(),
+            ..Default::default()
+        }),
+        tg_proxy: Some(ProxySettings {
+            url: "socks5://127.0.0.1:1080".to_string(),
+            username: "user".to_string(),
+            password: "PROXY_PASSWORD".to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let debug = format!("{:?}", config);
+    for secret in [
+        "VK_ACCESS_TOKEN",
+        "TG_ACCESS_TOKEN",
+        "VK_SECRET",
+        "TG_SECRET",
+        "PROXY_PASSWORD",
+    ] {
+        assert!(!debug.contains(secret), "{}", debug);
+    }
+    assert!(debug.contains("vk_secret: \"<redacted>\""), "{}", debug);
+    // Unset secrets stay empty
+    let debug = format!("{:?}", Config::default());
+    assert!(debug.contains("vk_access_token: \"\""), "{}", debug);
+}
@@ -1,10 +1,12 @@
 use log::debug;
-use serde_json::from_value;
+use serde::de::DeserializeOwned;
+use serde_json::{from_value, Value};
 
 use crate::{
     client::{
-        api_requests::api_call,
-        requests::{files_request, get_file, File, FileType},
+        api_requests::{api_call, ApiError},
+        rate_limit,
+        requests::{files_request, get_file, File, FileType, HyperRequestError},
     },
     structs::{
         config::Config,
@@ -20,16 +22,35 @@ use crate::{
 /// Download files from URLs
 /// # Arguments
 /// * `attachments` - Vector of attachments to download
+/// * `platform` - Platform which settings are used for downloading
+/// * `config` - Config to use
 ///
 /// # Returns
-/// * `Vec<File>` - Vector of downloaded files
-pub async fn download_files(attachments: Vec<Attachment>) -> Vec<File> {
+/// * `Result<Vec<File>, HyperRequestError>` - Vector of downloaded files, or the first download error
+pub async fn download_files(
+    attachments: Vec<Attachment>,
+    platform: Platform,
+    config: &Config,
+) -> Result<Vec<File>, HyperRequestError> {
     let mut files: Vec<File> = Vec::new();
     for attachment in attachments {
-        let file = get_file(&attachment.url).await.unwrap();
+        let file = get_file(&attachment.url, platform.clone(), config).await?;
         files.push(file);
     }
-    files
+    Ok(files)
+}
+/// Parses a VK upload server reply, turning its `error` field into [`ApiError::Upload`]
+fn parse_upload_reply<T: DeserializeOwned>(body: &str) -> Result<T, ApiError> {
+    let value: Value = serde_json::from_str(body)?;
+    if let Some(error) = value.get("error") {
+        return Err(ApiError::Upload(
+            error
+                .as_str()
+                .map(str::to_string)
+                .unwrap_or_else(|| error.to_string()),
+        ));
+    }
+    Ok(from_value(value)?)
 }
 struct VKUploadServers {
     photo: String,
@@ -45,12 +66,12 @@ struct VKUploadServers {
 /// * `peer_id` - Peer ID to send attachments to
 ///
 /// # Returns
-/// * `Result<String, String>` - String of uploaded attachments
+/// * `Result<String, ApiError>` - String of uploaded attachments
 pub async fn upload_vk_attachments(
     attachments: Vec<File>,
     config: &Config,
     peer_id: i64,
-) -> Result<String, String> {
+) -> Result<String, ApiError> {
     let mut message_attachments: String = "".to_string();
     let mut upload_servers = VKUploadServers {
         photo: "".to_string(),
@@ -69,8 +90,7 @@ pub async fn upload_vk_attachments(
                             config,
                         )
                         .await?,
-                    )
-                    .unwrap();
+                    )?;
                     upload_servers.photo = val.response.upload_url;
                 }
                 &upload_servers.photo
@@ -88,8 +108,7 @@ pub async fn upload_vk_attachments(
                             config,
                         )
                         .await?,
-                    )
-                    .unwrap();
+                    )?;
                     upload_servers.audio = val.response.upload_url;
                 }
                 &upload_servers.audio
@@ -104,8 +123,7 @@ pub async fn upload_vk_attachments(
                             config,
                         )
                         .await?,
-                    )
-                    .unwrap();
+                    )?;
                     upload_servers.doc = val.response.upload_url;
                 }
                 &upload_servers.doc
@@ -114,12 +132,9 @@ pub async fn upload_vk_attachments(
 
         match attachment.ftype {
             FileType::Photo => {
-                let uploaded_photo: VKMessagePhotoUploaded = serde_json::from_str(
-                    &files_request(server, &[attachment], None, Platform::VK)
-                        .await
-                        .unwrap(),
-                )
-                .unwrap();
+                let uploaded_photo: VKMessagePhotoUploaded = parse_upload_reply(
+                    &files_request(server, &[attachment], None, Platform::VK, config).await?,
+                )?;
                 let message_photo: VKMessagePhotoResponse = from_value(
                     api_call(
                         Platform::VK,
@@ -132,8 +147,7 @@ pub async fn upload_vk_attachments(
                         config,
                     )
                     .await?,
-                )
-                .unwrap();
+                )?;
                 message_attachments.push_str(&format!(
                     "photo{}_{},",
                     message_photo.response[0].owner_id, message_photo.response[0].id
@@ -141,11 +155,9 @@ pub async fn upload_vk_attachments(
             }
             _ => {
                 let ftype = attachment.ftype.clone();
-                let server_resp = files_request(server, &[attachment], None, Platform::VK)
-                    .await
-                    .unwrap();
-                let uploaded_doc: VKMessageDocumentUploaded =
-                    serde_json::from_str(&server_resp).unwrap();
+                let server_resp =
+                    files_request(server, &[attachment], None, Platform::VK, config).await?;
+                let uploaded_doc: VKMessageDocumentUploaded = parse_upload_reply(&server_resp)?;
                 let server_resp = api_call(
                     Platform::VK,
                     "docs.save",
@@ -155,18 +167,21 @@ pub async fn upload_vk_attachments(
                 .await?;
                 match ftype {
                     FileType::Audio | FileType::Voice => {
-                        let message_audio: VKMessageDocumentResponse =
-                            from_value(server_resp).unwrap();
-                        let audio_message = message_audio.response.audio_message.unwrap();
+                        let message_audio: VKMessageDocumentResponse = from_value(server_resp)?;
+                        let audio_message =
+                            message_audio.response.audio_message.ok_or_else(|| {
+                                ApiError::Upload("docs.save returned no audio_message".to_string())
+                            })?;
                         message_attachments.push_str(&format!(
                             "audio_message{}_{},",
                             audio_message.owner_id, audio_message.id
                         ))
                     }
                     _ => {
-                        let message_doc: VKMessageDocumentResponse =
-                            from_value(server_resp).unwrap();
-                        let doc = message_doc.response.doc.unwrap();
+                        let message_doc: VKMessageDocumentResponse = from_value(server_resp)?;
+                        let doc = message_doc.response.doc.ok_or_else(|| {
+                            ApiError::Upload("docs.save returned no doc".to_string())
+                        })?;
                         message_attachments.push_str(&format!("doc{}_{},", doc.owner_id, doc.id))
                     }
                 }
@@ -183,28 +198,27 @@ pub async fn upload_vk_attachments(
 /// * `config` - Config to use
 /// * `peer_id` - Chat ID to send attachments to
 /// * `message` - Message to send with attachments
+///
+/// # Returns
+/// * `Result<(), ApiError>` - Error if the files could not be read or sent
 pub async fn send_tg_attachment_files(
     attachments: Vec<File>,
     config: &Config,
     peer_id: i64,
     message: &str,
-) {
+) -> Result<(), ApiError> {
+    let chat_id = peer_id.to_string();
     if attachments.len() == 1 {
+        let method = format!("send{}", attachments[0].ftype.to_string().replace('_', ""));
+        rate_limit::wait(Platform::Telegram, &method, Some(&chat_id), config).await;
         files_request(
-            &format!(
-                "https://api.telegram.org/{}/send{}",
-                config.tg_access_token,
-                attachments[0].ftype.to_string().replace('_', ""),
-            ),
+            &config.tg_method_url(&method),
             &attachments,
-            Some(vec![
-                ("caption", message),
-                ("chat_id", &peer_id.to_string()),
-            ]),
+            Some(vec![("caption", message), ("chat_id", &chat_id)]),
             Platform::Telegram,
+            config,
         )
-        .await
-        .unwrap();
+        .await?;
     } else {
         let mut media: Vec<String> = Vec::new();
         for (index, f) in attachments.iter().enumerate() {
@@ -221,21 +235,20 @@ pub async fn send_tg_attachment_files(
             ));
         }
         debug!("MEDIA: {}", media.join(","));
+        rate_limit::wait(Platform::Telegram, "sendMediaGroup", Some(&chat_id), config).await;
         files_request(
-            &format!(
-                "https://api.telegram.org/{}/sendMediaGroup",
-                config.tg_access_token,
-            ),
+            &config.tg_method_url("sendMediaGroup"),
             &attachments,
             Some(vec![
                 ("media", &format!("[{}]", media.join(","))),
-                ("chat_id", &peer_id.to_string()),
+                ("chat_id", &chat_id),
             ]),
             Platform::Telegram,
+            config,
         )
-        .await
-        .unwrap();
+        .await?;
     }
+    Ok(())
 }
 
 /// Attachment struct